@@ -1,16 +1,18 @@
 use std::collections::BTreeSet;
 use std::sync::{Arc, Mutex, Condvar};
-use std::fs::File;
-use std::io::{BufReader, BufRead};
 use std::ops::DerefMut;
-use files::{write_json_file, read_json_file};
-use schedule::{World, Device, ScheduleEntry, GuestPath, DeviceOverride};
-use script_handler::ScriptHandler;
+use files::{write_json_file, read_json_file_or_backup};
+use schedule::{World, Device, ScheduleEntry, GuestPath, DeviceOverride, MacAddress, parse_days,
+               parse_time_of_day, parse_time_secs, parse_quota_mins};
+use firewall::{RuleApplier, FirewallStatus, DriftCheck};
 use config::{Config, reconcile_config};
-use chrono::{DateTime, Utc};
+use leases::{Lease, LeaseFormat, read_leases};
+use neighbors::read_neighbor_macs;
+use notify::notify_new_devices;
+use chrono::{DateTime, Utc, Local};
 use time::Duration;
-use ::script_handler::HandleScript;
-use ::script::write_script;
+use ::script::{Rule, RuleDiff, build_rules, diff_rules};
+use history::{EventSource, EventFilter, Event, world_events, append_events, read_events};
 use errors::{Result, ResultExt, ErrorKind};
 
 pub type AppServerWrapped = Arc<Mutex<AppServer>>;
@@ -40,28 +42,68 @@ impl Scheduler for AppServerSchedulerWrapped {
     }
 }
 
+fn secs_after(secs: Option<u32>, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    secs.map(|secs| now + Duration::seconds(i64::from(secs)))
+}
+
 pub fn run_expiration(wrapped_scheduler: &mut AppServerSchedulerWrapped) {
     let condvar = &wrapped_scheduler.condvar;
     let mut guard = wrapped_scheduler.wrapped_server.lock().unwrap();
+    let mut next_discovery = secs_after(guard.config.discovery_interval_secs, Utc::now());
+    let mut next_drift_check = secs_after(guard.config.drift_check_secs, Utc::now());
     loop {
+        let now : DateTime<Utc> = Utc::now();
         let option_max_date: Option<DateTime<Utc>> = {
             let world = &guard.world;
             world.get_soonest_event_time()
+                .into_iter()
+                .chain(world.get_next_rule_time(now, &Local))
+                .chain(world.get_next_quota_time(now, guard.config.quota_reset_time, &Local))
+                .chain(world.get_next_quarantine_time(guard.config.unknown_device_policy, now))
+                .chain(guard.firewall.next_retry())
+                .min()
         };
-        let now : DateTime<Utc> = Utc::now();
-        let dur = option_max_date.map(|max_date|
+        let wake_date = option_max_date.into_iter()
+            .chain(next_discovery)
+            .chain(next_drift_check)
+            .min();
+        let dur = wake_date.map(|max_date|
             max_date.signed_duration_since(now)).unwrap_or_else(|| Duration::days(30));
         let std_dur = dur.to_std().unwrap_or_else(|_| ::std::time::Duration::new(0, 0));
-        let (g2, _) = condvar.wait_timeout(guard, std_dur).unwrap();
+        let (g2, wait) = condvar.wait_timeout(guard, std_dur).unwrap();
         guard = g2;
-        {
+        let now = Utc::now();
+        let drift_check_due = next_drift_check.map_or(false, |t| t <= now);
+        // A wake up only for discovery or the drift check leaves the world
+        // alone, or every check would rewrite the state file and reapply the
+        // rules. Discovery saves the world itself when it finds a change.
+        let event_due = !wait.timed_out() || option_max_date.map_or(false, |t| t <= now);
+        if event_due {
+            guard.account_quotas();
             {
                 let world = &mut guard.deref_mut().world;
-                let now = Utc::now();
                 world.expire_bounded(now);
+                world.apply_weekly_rules(now, &Local);
             }
-            guard.refresh_world().unwrap_or_else(|err| println!("{:?}", err));
-        };
+            guard.refresh_world_from(EventSource::Scheduler)
+                .unwrap_or_else(|err| println!("{:?}", err));
+        }
+        if next_discovery.map_or(false, |t| t <= now) {
+            guard.refresh_devices_from(EventSource::Scheduler)
+                .unwrap_or_else(|err| println!("{:?}", err));
+            next_discovery = secs_after(guard.config.discovery_interval_secs, now);
+        }
+        if drift_check_due {
+            if let Some(check) = guard.firewall.drift_check() {
+                // Listing the live firewall runs commands, which API requests
+                // should not have to wait for.
+                drop(guard);
+                let in_place = check.in_place();
+                guard = wrapped_scheduler.wrapped_server.lock().unwrap();
+                guard.check_firewall(&check, in_place, Utc::now());
+            }
+            next_drift_check = secs_after(guard.config.drift_check_secs, now);
+        }
     }
 }
 
@@ -75,9 +117,67 @@ impl<'a> RequestErrExt<'a> for Option<&'a str> {
     }
 }
 
+pub fn require_mac(mac_param: Option<&str>) -> Result<MacAddress> {
+    mac_param.require_param("Missing mac parameter".to_owned())?.parse()
+}
+
+pub fn parse_guest_path(allow_param: Option<&str>) -> Result<GuestPath> {
+    let allow_str = allow_param.require_param("Missing allow parameter".to_owned())?;
+    Ok(if allow_str.to_lowercase() == "true" {
+        GuestPath::Open
+    } else {
+        GuestPath::Closed
+    })
+}
+
+pub fn parse_device_override(override_param: Option<&str>) -> Result<Option<DeviceOverride>> {
+    let override_str = override_param.require_param("Missing override paramter".to_owned())?;
+    Ok(if override_str.to_lowercase() == "null" {
+        None
+    } else if override_str.to_lowercase() == "true" {
+        Some(DeviceOverride::Open)
+    } else {
+        Some(DeviceOverride::Closed)
+    })
+}
+
+/// A change to the schedule which can either be made or previewed.
+#[derive(Debug, Clone)]
+pub enum Change {
+    OpenDevice(MacAddress, Option<DateTime<Utc>>),
+    CloseDevice(MacAddress),
+    OpenGroup(BTreeSet<MacAddress>, Option<DateTime<Utc>>),
+    CloseGroup(BTreeSet<MacAddress>),
+    GuestPath(GuestPath, Option<DateTime<Utc>>),
+    DeviceOverride(Option<DeviceOverride>, Option<DateTime<Utc>>),
+}
+
+impl Change {
+    fn apply(self, world: &mut World) -> Result<()> {
+        match self {
+            Change::OpenDevice(mac, time_bound) => world.open_device(&mac, time_bound),
+            Change::CloseDevice(mac) => world.close_device(&mac),
+            Change::OpenGroup(macs, time_bound) => world.open_devices(&macs, time_bound),
+            Change::CloseGroup(macs) => world.close_devices(&macs),
+            Change::GuestPath(item, time_bound) => {
+                world.schedule.guest_entry = ScheduleEntry { item, time_bound };
+                Ok(())
+            }
+            Change::DeviceOverride(item, time_bound) => {
+                world.schedule.override_entry = item.map(|item| {
+                    ScheduleEntry { item, time_bound }
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
 pub struct AppServer {
     pub world: World,
-    pub handler: ScriptHandler,
+    /// The world as of the last history entry.
+    pub recorded_world: World,
+    pub firewall: RuleApplier,
     pub config: Config,
     pub config_file: String,
 }
@@ -87,100 +187,408 @@ impl AppServer {
                        mac_param: Option<&str>,
                        time_bound: Option<DateTime<Utc>>)
                        -> Result<()> {
-        let mac = mac_param.require_param("Missing mac parameter".to_owned())?;
-        self.world.open_device(mac, time_bound)?;
-        self.refresh_world()
+        let mac = require_mac(mac_param)?;
+        self.make_change(Change::OpenDevice(mac, time_bound))
     }
 
     pub fn close_device(&mut self, mac_param: Option<&str>) -> Result<()> {
-        let mac = mac_param.require_param("Missing mac parameter".to_owned())?;
-        self.world.close_device(mac)?;
+        let mac = require_mac(mac_param)?;
+        self.make_change(Change::CloseDevice(mac))
+    }
+
+    pub fn extend_device(&mut self, mac_param: Option<&str>, secs_param: Option<&str>)
+                         -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let secs_str = secs_param.require_param("Missing time_secs parameter".to_owned())?;
+        let secs = parse_time_secs(secs_str)?;
+        self.account_quotas();
+        self.world.extend_device(&mac, secs, Utc::now())?;
+        self.refresh_world()
+    }
+
+    pub fn schedule_device(&mut self,
+                           mac_param: Option<&str>,
+                           start: Option<DateTime<Utc>>,
+                           time_bound: Option<DateTime<Utc>>)
+                           -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let start = start.ok_or_else(|| {
+            ErrorKind::RequestError("Missing start parameter".to_owned())
+        })?;
+        self.world.schedule_device(&mac, start, time_bound, Utc::now())?;
+        self.refresh_world()
+    }
+
+    pub fn cancel_pending(&mut self, mac_param: Option<&str>, start: Option<DateTime<Utc>>)
+                          -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let start = start.ok_or_else(|| {
+            ErrorKind::RequestError("Missing start parameter".to_owned())
+        })?;
+        self.world.cancel_pending(&mac, start)?;
         self.refresh_world()
     }
 
+    pub fn group_macs(&self, group_param: Option<&str>) -> Result<BTreeSet<MacAddress>> {
+        let group = group_param.require_param("Missing group parameter".to_owned())?;
+        self.config
+            .groups
+            .get(group)
+            .cloned()
+            .ok_or_else(|| ErrorKind::RequestError("group not found".to_owned()).into())
+    }
+
+    pub fn open_group(&mut self,
+                      group_param: Option<&str>,
+                      time_bound: Option<DateTime<Utc>>)
+                      -> Result<()> {
+        let macs = self.group_macs(group_param)?;
+        self.make_change(Change::OpenGroup(macs, time_bound))
+    }
+
+    pub fn close_group(&mut self, group_param: Option<&str>) -> Result<()> {
+        let macs = self.group_macs(group_param)?;
+        self.make_change(Change::CloseGroup(macs))
+    }
+
     pub fn set_guest_path(&mut self,
                           allow_param: Option<&str>,
                           time_bound: Option<DateTime<Utc>>)
                           -> Result<()> {
-        let allow_str = allow_param.require_param("Missing allow parameter".to_owned())?;
-        let allow = if allow_str.to_lowercase() == "true" {
-            GuestPath::Open
-        } else {
-            GuestPath::Closed
-        };
-        self.world.schedule.guest_entry = ScheduleEntry {
-            item: allow,
-            time_bound,
-        };
-        self.refresh_world()
+        let allow = parse_guest_path(allow_param)?;
+        self.make_change(Change::GuestPath(allow, time_bound))
     }
 
     pub fn set_device_override(&mut self,
                                override_param: Option<&str>,
                                time_bound: Option<DateTime<Utc>>)
                                -> Result<()> {
-        let override_str = override_param.require_param("Missing override paramter".to_owned())?;
-        let override_arg = if override_str.to_lowercase() == "null" {
-            None
-        } else if override_str.to_lowercase() == "true" {
-            Some(DeviceOverride::Open)
-        } else {
-            Some(DeviceOverride::Closed)
-        };
-        self.world.schedule.override_entry = override_arg.map(|i| {
-            ScheduleEntry {
-                item: i,
-                time_bound,
-            }
-        });
+        let override_arg = parse_device_override(override_param)?;
+        self.make_change(Change::DeviceOverride(override_arg, time_bound))
+    }
+
+    /// Applies `change` to a copy of the world, so a change that fails part
+    /// way through (say one group member is out of quota) leaves nothing
+    /// behind.
+    fn make_change(&mut self, change: Change) -> Result<()> {
+        self.account_quotas();
+        let mut world = self.world.clone();
+        change.apply(&mut world)?;
+        self.world = world;
+        self.refresh_world()
+    }
+
+    /// The rules `change` would lead to, worked out on a copy of the world.
+    /// Nothing is saved or applied.
+    pub fn preview_change(&self, change: Change) -> Result<RuleDiff> {
+        let now = Utc::now();
+        let mut world = self.world.clone();
+        world.account_quotas(now, self.config.quota_reset_time, &Local);
+        let current = self.build_rules(&world, now);
+        change.apply(&mut world)?;
+        Ok(diff_rules(&current, &self.build_rules(&world, now)))
+    }
+
+    pub fn add_weekly_rule(&mut self,
+                           mac_param: Option<&str>,
+                           days_param: Option<&str>,
+                           start_param: Option<&str>,
+                           end_param: Option<&str>)
+                           -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let days_str = days_param.require_param("Missing days parameter".to_owned())?;
+        let start_str = start_param.require_param("Missing start parameter".to_owned())?;
+        let end_str = end_param.require_param("Missing end parameter".to_owned())?;
+        let days = parse_days(days_str)?;
+        let start = parse_time_of_day(start_str)?;
+        let end = parse_time_of_day(end_str)?;
+        self.world.add_weekly_rule(&mac, days, start, end)?;
+        self.refresh_world()
+    }
+
+    pub fn remove_weekly_rule(&mut self, id_param: Option<&str>) -> Result<()> {
+        let id_str = id_param.require_param("Missing id parameter".to_owned())?;
+        let id = id_str.parse::<u32>()
+            .chain_err(|| ErrorKind::RequestError("Failed to parse rule id".to_owned()))?;
+        self.world.remove_weekly_rule(id)?;
         self.refresh_world()
     }
 
-    pub fn add_device(&mut self, mac_param: Option<&str>, name_param: Option<&str>) -> Result<()> {
-        let mac = mac_param.require_param("Missing mac parameter".to_owned())?;
+    pub fn add_device(&mut self,
+                      mac_param: Option<&str>,
+                      name_param: Option<&str>,
+                      quota_param: Option<&str>)
+                      -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        self.check_not_ignored(&mac)?;
         let name = name_param.require_param("Missing name parameter".to_owned())?;
+        let daily_quota_mins = match quota_param {
+            Some(q) => Some(parse_quota_mins(q)?),
+            None => None,
+        };
         let dev = Device {
-            mac: mac.to_owned(),
+            mac,
             name: name.to_owned(),
+            daily_quota_mins,
         };
+        // Adding a known MAC again updates it rather than listing it twice.
+        self.config.known_devices.retain(|d| d.mac != dev.mac);
         self.config.known_devices.insert(dev);
         self.write_config()?;
-        let mut devs = BTreeSet::new();
-        read_dhcp_devices(&self.config.dhcp_lease_file, &mut devs)?;
-        let reconcile_result = {
-            reconcile_config(&self.config, &devs, &mut self.world)
+        self.refresh_devices()
+    }
+
+    /// Makes an unknown device known, keeping its DHCP hostname unless given
+    /// a name. It starts closed unless `open_param` is true or a time bound
+    /// is given.
+    pub fn adopt_device(&mut self,
+                        mac_param: Option<&str>,
+                        name_param: Option<&str>,
+                        open_param: Option<&str>,
+                        group_param: Option<&str>,
+                        time_bound: Option<DateTime<Utc>>)
+                        -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let unknown = self.world
+            .unknown_devices
+            .iter()
+            .find(|d| d.mac == mac)
+            .cloned()
+            .ok_or_else(|| ErrorKind::RequestError("mac is not an unknown device".to_owned()))?;
+        let name = match name_param {
+            Some(name) => name.to_owned(),
+            None if unknown.name != "*" => unknown.name,
+            None => return Err(ErrorKind::RequestError("Missing name parameter".to_owned()).into()),
+        };
+        let open = match open_param {
+            Some(open) => open.to_lowercase() == "true",
+            None => time_bound.is_some(),
+        };
+        if !open && time_bound.is_some() {
+            return Err(ErrorKind::RequestError("Only an open device takes a time bound".to_owned())
+                .into());
+        }
+        let dev = Device {
+            mac: mac.clone(),
+            name,
+            daily_quota_mins: None,
+        };
+        if open {
+            // Opening is tried first so a failure leaves the device unknown.
+            let mut world = self.world.clone();
+            world.closed_devices.insert(dev.clone());
+            world.open_device(&mac, time_bound)?;
+        }
+        let mut config = self.config.clone();
+        if let Some(group) = group_param {
+            config.groups.entry(group.to_owned()).or_insert_with(BTreeSet::new)
+                .insert(mac.clone());
+        }
+        config.known_devices.insert(dev);
+        self.replace_config(config)?;
+        self.refresh_devices()?;
+        if open {
+            self.make_change(Change::OpenDevice(mac, time_bound))?;
+        }
+        Ok(())
+    }
+
+    /// Lets a device through for good and stops listing it as unknown. The
+    /// name defaults to its DHCP hostname.
+    pub fn ignore_device(&mut self, mac_param: Option<&str>, name_param: Option<&str>)
+                         -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        if self.known_device(&mac).is_ok() {
+            return Err(ErrorKind::RequestError("mac is a known device".to_owned()).into());
+        }
+        let name = match name_param {
+            Some(name) => name.to_owned(),
+            None => {
+                self.world
+                    .unknown_devices
+                    .iter()
+                    .find(|d| d.mac == mac)
+                    .map_or_else(|| "*".to_owned(), |d| d.name.clone())
+            }
         };
-        if reconcile_result.updated_world {
-            self.refresh_world()?;
+        let mut config = self.config.clone();
+        config.ignored_devices.retain(|d| d.mac != mac);
+        config.ignored_devices.insert(Device {
+            mac,
+            name,
+            daily_quota_mins: None,
+        });
+        self.replace_config(config)?;
+        self.refresh_devices()
+    }
+
+    pub fn unignore_device(&mut self, mac_param: Option<&str>) -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let mut config = self.config.clone();
+        let len = config.ignored_devices.len();
+        config.ignored_devices.retain(|d| d.mac != mac);
+        if config.ignored_devices.len() == len {
+            return Err(ErrorKind::RequestError("mac not ignored".to_owned()).into());
+        }
+        self.replace_config(config)?;
+        self.refresh_devices()
+    }
+
+    /// A device is either known or ignored, never both.
+    fn check_not_ignored(&self, mac: &MacAddress) -> Result<()> {
+        if self.config.ignored_devices.iter().any(|d| d.mac == *mac) {
+            return Err(ErrorKind::RequestError("mac is an ignored device".to_owned()).into());
         }
         Ok(())
     }
 
+    fn known_device(&self, mac: &MacAddress) -> Result<Device> {
+        self.config
+            .known_devices
+            .iter()
+            .find(|d| d.mac == *mac)
+            .cloned()
+            .ok_or_else(|| ErrorKind::RequestError("mac not found".to_owned()).into())
+    }
+
+    pub fn remove_device(&mut self, mac_param: Option<&str>) -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let dev = self.known_device(&mac)?;
+        let mut config = self.config.clone();
+        config.known_devices.remove(&dev);
+        for macs in config.groups.values_mut() {
+            macs.remove(&mac);
+        }
+        self.replace_config(config)?;
+        self.world.weekly_rules.retain(|r| r.mac != mac);
+        self.world.quota_usage.retain(|u| u.mac != mac);
+        self.refresh_devices()
+    }
+
+    pub fn rename_device(&mut self, mac_param: Option<&str>, name_param: Option<&str>)
+                         -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let name = name_param.require_param("Missing name parameter".to_owned())?;
+        let dev = self.known_device(&mac)?;
+        let mut config = self.config.clone();
+        config.known_devices.remove(&dev);
+        config.known_devices.insert(Device { name: name.to_owned(), ..dev });
+        self.replace_config(config)?;
+        self.refresh_devices()
+    }
+
+    pub fn change_device_mac(&mut self,
+                             mac_param: Option<&str>,
+                             new_mac_param: Option<&str>)
+                             -> Result<()> {
+        let mac = require_mac(mac_param)?;
+        let new_mac = new_mac_param.require_param("Missing new_mac parameter".to_owned())?
+            .parse::<MacAddress>()?;
+        let dev = self.known_device(&mac)?;
+        if self.known_device(&new_mac).is_ok() {
+            return Err(ErrorKind::RequestError("new mac is already known".to_owned()).into());
+        }
+        self.check_not_ignored(&new_mac)?;
+        let mut world = self.world.clone();
+        world.change_mac(&mac, &new_mac)?;
+        let mut config = self.config.clone();
+        config.known_devices.remove(&dev);
+        config.known_devices.insert(Device { mac: new_mac.clone(), ..dev });
+        for macs in config.groups.values_mut() {
+            if macs.remove(&mac) {
+                macs.insert(new_mac.clone());
+            }
+        }
+        self.replace_config(config)?;
+        self.world = world;
+        self.refresh_devices()?;
+        // The world was moved over already, so reconciling changed nothing.
+        self.refresh_world()
+    }
+
     pub fn refresh_devices(&mut self) -> Result<()> {
+        self.refresh_devices_from(EventSource::Api)
+    }
+
+    pub fn refresh_devices_from(&mut self, source: EventSource) -> Result<()> {
         let mut devs = BTreeSet::new();
-        read_dhcp_devices(&self.config.dhcp_lease_file, &mut devs)?;
+        let leases =
+            read_dhcp_devices(&self.config.dhcp_lease_file, self.config.lease_format, &mut devs)?;
+        self.world.leases = leases;
+        let neighbor_macs = read_neighbor_macs(&self.config);
         let reconcile_result = {
-            reconcile_config(&self.config, &devs, &mut self.world)
+            reconcile_config(&self.config, &devs, &neighbor_macs, &mut self.world)
         };
-        if reconcile_result.updated_world {
-            self.refresh_world()?;
+        let new_devices = self.world.record_seen_devices(Utc::now());
+        if reconcile_result.updated_world || !new_devices.is_empty() {
+            self.refresh_world_from(source)?;
+        } else {
+            self.record_history(source);
         }
+        notify_new_devices(&self.config, new_devices);
         Ok(())
     }
 
-    fn handle_script(&self) -> Result<()> {
-        let mut script = String::new();
-        write_script(&self.world,
-                     "old_blocked_devices",
-                     "blocked_devices",
-                     &self.config.exit_interfaces,
-                     &mut script);
-        self.handler.handle(&script)
+    fn account_quotas(&mut self) {
+        self.world.account_quotas(Utc::now(), self.config.quota_reset_time, &Local);
+    }
+
+    fn build_rules(&self, world: &World, now: DateTime<Utc>) -> Vec<Rule> {
+        build_rules(world, &self.config.exit_interfaces, self.config.unknown_device_policy, now)
+    }
+
+    /// Applies the rules for the current world. Also run at startup, which
+    /// gives the drift check something to compare against.
+    pub fn apply_rules(&mut self) -> Result<()> {
+        let now = Utc::now();
+        let rules = self.build_rules(&self.world, now);
+        self.firewall.apply(rules, now)
+    }
+
+    fn check_firewall(&mut self, check: &DriftCheck, in_place: Result<bool>, now: DateTime<Utc>) {
+        match in_place.and_then(|in_place| self.firewall.repair_drift(check, in_place, now)) {
+            Ok(true) => println!("Firewall rules went missing and were applied again"),
+            Ok(false) => {}
+            Err(err) => println!("Failed to check firewall rules: {:?}", err),
+        }
+    }
+
+    /// A copy of the world with screen time counted up to now, so remaining
+    /// quotas are current between scheduler wake ups.
+    pub fn current_world(&self) -> World {
+        let mut world = self.world.clone();
+        world.account_quotas(Utc::now(), self.config.quota_reset_time, &Local);
+        world
+    }
+
+    pub fn firewall_status(&self) -> FirewallStatus {
+        self.firewall.status()
     }
 
-    pub fn refresh_world(&self) -> Result<()> {
+    pub fn refresh_world(&mut self) -> Result<()> {
+        self.refresh_world_from(EventSource::Api)
+    }
+
+    pub fn refresh_world_from(&mut self, source: EventSource) -> Result<()> {
         self.write_world()?;
-        self.handle_script()
+        self.record_history(source);
+        self.apply_rules()
+    }
+
+    fn record_history(&mut self, source: EventSource) {
+        let events = world_events(&self.recorded_world, &self.world, Utc::now(), source);
+        self.recorded_world = self.world.clone();
+        if let Some(ref history_file) = self.config.history_file {
+            append_events(history_file, self.config.history_max_bytes, &events)
+                .unwrap_or_else(|err| println!("{:?}", err));
+        }
+    }
+
+    pub fn history(&self, filter: &EventFilter) -> Result<Vec<Event>> {
+        match self.config.history_file {
+            Some(ref history_file) => read_events(history_file, filter),
+            None => Ok(vec![]),
+        }
     }
 
     pub fn write_world(&self) -> Result<()> {
@@ -193,29 +601,252 @@ impl AppServer {
             .chain_err(|| "Failed to write new config file")
     }
 
+    /// Writes `config` and only then makes it current, so a failed write
+    /// leaves memory matching the file.
+    fn replace_config(&mut self, config: Config) -> Result<()> {
+        write_json_file(&self.config_file, &config)
+            .chain_err(|| "Failed to write new config file")?;
+        self.config = config;
+        Ok(())
+    }
+
     pub fn read_or_create_world(&mut self) -> Result<()> {
-        self.world = read_json_file(&self.config.state_file).unwrap_or_default();
+        self.world = read_json_file_or_backup(&self.config.state_file).unwrap_or_default();
+        self.recorded_world = self.world.clone();
         self.write_world()
     }
 }
 
-pub fn read_dhcp_devices(dhcp_leases_file: &str, devs: &mut BTreeSet<Device>) -> Result<()> {
-    let reader =
-        File::open(dhcp_leases_file).chain_err(|| "Failed to open dhcp lease file.")?;
-    let buf_reader = BufReader::new(reader);
-    for line_result in buf_reader.lines() {
-        let line = line_result.chain_err(|| "Failed to read line")?;
-        let parts: Vec<String> = line.splitn(4, |c: char| c.is_whitespace())
-            .into_iter()
-            .map(|s| s.to_string())
-            .collect();
-        let mac = &parts[1];
-        let name = &parts[3];
-        let dev = Device {
-            mac: mac.clone(),
-            name: name.clone(),
+/// Adds a device to `devs` for each lease, and returns the leases.
+pub fn read_dhcp_devices(dhcp_leases_file: &str,
+                         format: LeaseFormat,
+                         devs: &mut BTreeSet<Device>)
+                         -> Result<Vec<Lease>> {
+    let leases = read_leases(dhcp_leases_file, format)?;
+    for lease in &leases {
+        devs.insert(Device {
+            mac: lease.mac.clone(),
+            name: lease.hostname.clone().unwrap_or_else(|| "*".to_owned()),
+            daily_quota_mins: None,
+        });
+    }
+    Ok(leases)
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use app_server::AppServer;
+    use files::test::TempDir;
+    use config::test::config_fixture;
+    use schedule::test::world_fixture;
+    use chrono::{Utc, Local, Duration};
+    use firewall::{RuleApplier, RecordingBackend};
+    use types::QuotaUsage;
+    use mac::test::mac;
+
+    /// An app server whose files live in a fresh temp directory, with
+    /// `leases` as its dnsmasq lease file.
+    pub fn app_server_fixture(name: &str, leases: &str) -> (TempDir, AppServer) {
+        let dir = TempDir::new(&format!("app-{}", name));
+        let mut config = config_fixture();
+        config.state_file = dir.path("state.json");
+        config.dhcp_lease_file = dir.path("leases");
+        File::create(&config.dhcp_lease_file).unwrap().write_all(leases.as_bytes()).unwrap();
+        let app_server = AppServer {
+            world: world_fixture(),
+            recorded_world: world_fixture(),
+            firewall: RuleApplier::new(Box::new(RecordingBackend::default())),
+            config,
+            config_file: dir.path("config.json"),
         };
-        devs.insert(dev);
+        (dir, app_server)
+    }
+
+    #[test]
+    fn add_device_replaces_mac() {
+        let (_dir, mut app_server) = app_server_fixture("add", "");
+        app_server.add_device(Some("00-00-00-00-12-34"), Some("TV2"), Some("60")).unwrap();
+        let devs: Vec<_> = app_server.config
+            .known_devices
+            .iter()
+            .filter(|d| d.mac == mac("00:00:00:00:12:34"))
+            .collect();
+        assert_eq!(1, devs.len());
+        assert_eq!(Some(60), devs[0].daily_quota_mins);
+        let open = app_server.world.schedule.open_device_entries.iter()
+            .find(|e| e.item.mac == mac("00:00:00:00:12:34"))
+            .unwrap();
+        assert_eq!(Some(60), open.item.daily_quota_mins);
+    }
+
+    #[test]
+    fn failed_group_open_changes_nothing() {
+        let (_dir, mut app_server) = app_server_fixture("group", "");
+        let tv4 = mac("00:00:00:00:ab:cd");
+        let group = [mac("00:00:00:00:bb:bb"), tv4.clone()].iter().cloned().collect();
+        app_server.config.groups.insert("tvs".to_owned(), group);
+        let mut dev = app_server.world.closed_devices.iter()
+            .find(|d| d.mac == tv4)
+            .cloned()
+            .unwrap();
+        app_server.world.closed_devices.remove(&dev);
+        dev.daily_quota_mins = Some(10);
+        app_server.world.closed_devices.insert(dev);
+        app_server.account_quotas();
+        app_server.world.quota_usage[0].used_secs = 600;
+        let before = app_server.world.clone();
+
+        assert!(app_server.open_group(Some("tvs"), None).is_err());
+        assert_eq!(before.closed_devices, app_server.world.closed_devices);
+        assert_eq!(before.schedule, app_server.world.schedule);
+    }
+
+    #[test]
+    fn refresh_devices_keeps_leases() {
+        let (_dir, mut app_server) =
+            app_server_fixture("leases", "1486637130 00:00:00:00:77:77 192.168.1.20 Phone *\n");
+        app_server.refresh_devices().unwrap();
+        assert_eq!(1, app_server.world.leases.len());
+        let lease = &app_server.world.leases[0];
+        assert_eq!(mac("00:00:00:00:77:77"), lease.mac);
+        assert_eq!("192.168.1.20", lease.ip.to_string());
+        assert!(lease.expiry.is_some());
+        assert!(app_server.world.unknown_devices.iter().any(|d| d.mac == lease.mac));
+    }
+
+    #[test]
+    fn remove_device_forgets_rules_and_usage() {
+        let (_dir, mut app_server) = app_server_fixture("remove", "");
+        let tv2 = mac("00:00:00:00:12:34");
+        app_server.add_weekly_rule(Some(tv2.as_str()), Some("mon"), Some("08:00"), Some("09:00"))
+            .unwrap();
+        app_server.world.quota_usage.push(QuotaUsage {
+            mac: tv2.clone(),
+            period_start: Utc::now(),
+            used_secs: 60,
+            remaining_mins: 0,
+        });
+        app_server.remove_device(Some(tv2.as_str())).unwrap();
+        assert!(app_server.world.weekly_rules.is_empty());
+        assert!(app_server.world.quota_usage.is_empty());
+        assert!(app_server.config.known_devices.iter().all(|d| d.mac != tv2));
+    }
+
+    #[test]
+    fn failed_mac_change_changes_nothing() {
+        let (dir, mut app_server) = app_server_fixture("change-mac", "");
+        app_server.config_file = dir.path("missing/config.json");
+        let world = app_server.world.clone();
+        let config = app_server.config.clone();
+        assert!(app_server.change_device_mac(Some("00:00:00:00:12:34"), Some("00:00:00:00:56:78"))
+            .is_err());
+        assert_eq!(world, app_server.world);
+        assert_eq!(config, app_server.config);
+    }
+
+    #[test]
+    fn adopt_device() {
+        let (_dir, mut app_server) =
+            app_server_fixture("adopt",
+                               "1486637130 00:00:00:00:77:77 192.168.1.20 Phone *\n\
+                                1486637130 00:00:00:00:88:88 192.168.1.21 * *\n");
+        app_server.refresh_devices().unwrap();
+        let config = app_server.config.clone();
+        let later = Utc::now() + Duration::minutes(30);
+
+        assert!(app_server.adopt_device(Some("00:00:00:00:12:34"), Some("TV"), None, None, None)
+            .is_err());
+        assert!(app_server.adopt_device(Some("00:00:00:00:88:88"), None, None, None, None)
+            .is_err());
+        assert!(app_server.adopt_device(Some("00:00:00:00:88:88"),
+                                        Some("Tablet"),
+                                        Some("false"),
+                                        None,
+                                        Some(later))
+            .is_err());
+        assert_eq!(config, app_server.config);
+
+        // The name falls back to the DHCP hostname.
+        app_server.adopt_device(Some("00:00:00:00:77:77"), None, None, Some("kids"), None)
+            .unwrap();
+        let phone = app_server.known_device(&mac("00:00:00:00:77:77")).unwrap();
+        assert_eq!("Phone", phone.name);
+        assert!(app_server.config.groups["kids"].contains(&phone.mac));
+        assert!(app_server.world.closed_devices.contains(&phone));
+
+        app_server.adopt_device(Some("00:00:00:00:88:88"), Some("Tablet"), None, None, Some(later))
+            .unwrap();
+        let entry = app_server.world.schedule.open_device_entries.iter()
+            .find(|e| e.item.mac == mac("00:00:00:00:88:88"))
+            .unwrap();
+        assert_eq!(("Tablet", Some(later)), (entry.item.name.as_ref(), entry.time_bound));
+        assert!(app_server.world.unknown_devices.is_empty());
+    }
+
+    #[test]
+    fn extend_device_bounded() {
+        let (_dir, mut app_server) = app_server_fixture("extend", "");
+        for secs in &["9223372036854775807", "-60", "0", "soon"] {
+            assert!(app_server.extend_device(Some("00:00:00:00:12:34"), Some(secs)).is_err(),
+                    "{}",
+                    secs);
+        }
+        app_server.extend_device(Some("00:00:00:00:12:34"), Some("600")).unwrap();
+        let entry = app_server.world.schedule.open_device_entries.iter()
+            .find(|e| e.item.mac == mac("00:00:00:00:12:34"))
+            .unwrap();
+        assert!(entry.time_bound.unwrap() > Utc::now());
+    }
+
+    #[test]
+    fn quota_counted_when_read() {
+        let (_dir, mut app_server) = app_server_fixture("quota", "");
+        // Well away from a reset, which would start the count over.
+        app_server.config.quota_reset_time = (Local::now() + Duration::hours(12)).time();
+        for bad in &["0", "-5", "100000"] {
+            assert!(app_server.add_device(Some("00:00:00:00:12:34"), Some("TV2"), Some(bad))
+                .is_err());
+        }
+        app_server.add_device(Some("00:00:00:00:12:34"), Some("TV2"), Some("60")).unwrap();
+        app_server.account_quotas();
+        app_server.world.quota_checked = Some(Utc::now() - Duration::minutes(10));
+
+        let world = app_server.current_world();
+        let usage = world.quota_usage.iter().find(|u| u.mac == mac("00:00:00:00:12:34")).unwrap();
+        assert!(usage.used_secs >= 600);
+        assert!(usage.remaining_mins <= 50);
+    }
+
+    #[test]
+    fn drift_checked_once_applied() {
+        let (_dir, mut app_server) = app_server_fixture("drift", "");
+        assert!(app_server.firewall.drift_check().is_none());
+        app_server.apply_rules().unwrap();
+        assert!(app_server.firewall.drift_check().is_some());
+    }
+
+    #[test]
+    fn rename_keeps_config_on_failed_write() {
+        let (dir, mut app_server) = app_server_fixture("rename", "");
+        let tv = mac("00:00:00:00:12:34");
+        app_server.rename_device(Some(tv.as_str()), Some("Den")).unwrap();
+        assert_eq!("Den", app_server.known_device(&tv).unwrap().name);
+        app_server.config_file = dir.path("missing/config.json");
+        assert!(app_server.rename_device(Some(tv.as_str()), Some("Attic")).is_err());
+        assert_eq!("Den", app_server.known_device(&tv).unwrap().name);
+    }
+
+    #[test]
+    fn known_or_ignored() {
+        let (_dir, mut app_server) = app_server_fixture("ignored", "");
+        app_server.ignore_device(Some("00:00:00:00:20:20"), Some("printer")).unwrap();
+        assert!(app_server.ignore_device(Some("00:00:00:00:12:34"), None).is_err());
+        assert!(app_server.add_device(Some("00:00:00:00:20:20"), Some("TV9"), None).is_err());
+        assert!(app_server.change_device_mac(Some("00:00:00:00:12:34"), Some("00:00:00:00:20:20"))
+            .is_err());
+        app_server.unignore_device(Some("00:00:00:00:20:20")).unwrap();
+        app_server.add_device(Some("00:00:00:00:20:20"), Some("TV9"), None).unwrap();
     }
-    Ok(())
 }
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use chrono::{DateTime, Utc, Duration};
+use iron::{BeforeMiddleware, Request, IronResult, IronError};
+use iron::headers::{Cookie, Authorization, Bearer};
+use iron::status;
+use pbkdf2::{pbkdf2_simple, pbkdf2_check};
+use rand::{thread_rng, Rng};
+
+use errors::{Result, ResultExt, ErrorKind};
+
+pub const SESSION_COOKIE: &str = "device_blocker_session";
+pub const SESSION_DAYS: i64 = 30;
+// Routers have slow CPUs, keep logins under a second or so.
+const HASH_ROUNDS: u32 = 10000;
+// Reachable without a session so the UI can show its login form.
+const PUBLIC_PATHS: &[&str] = &["", "bundle.js", "api/login"];
+// Failed logins in a row, from anywhere, before all logins are locked for
+// LOCKOUT_SECS. Shared so parallel connections cannot guess faster.
+const MAX_FAILURES: u32 = 5;
+const LOCKOUT_SECS: i64 = 60;
+
+pub fn hash_password(password: &str) -> Result<String> {
+    pbkdf2_simple(password, HASH_ROUNDS).chain_err(|| "Failed to hash password")
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    locked_until: Option<DateTime<Utc>>,
+}
+
+pub struct Auth {
+    password_hash: Option<String>,
+    sessions: Mutex<BTreeMap<String, DateTime<Utc>>>,
+    failures: Mutex<Failures>,
+}
+
+pub type AuthWrapped = Arc<Auth>;
+
+impl Auth {
+    pub fn new(password_hash: Option<String>) -> Auth {
+        Auth {
+            password_hash,
+            sessions: Mutex::new(BTreeMap::new()),
+            failures: Mutex::new(Failures::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    /// Checks `password` and starts a new session, returning its token.
+    /// After `MAX_FAILURES` wrong passwords in a row every login is refused
+    /// for `LOCKOUT_SECS`.
+    pub fn login(&self, password: &str, now: DateTime<Utc>) -> Result<String> {
+        let hash = match self.password_hash {
+            Some(ref h) => h,
+            None => {
+                return Err(ErrorKind::RequestError("No admin password configured".to_owned())
+                    .into())
+            }
+        };
+        {
+            let mut failures = self.failures.lock().unwrap();
+            if failures.locked_until.map(|t| t > now).unwrap_or(false) {
+                return Err(ErrorKind::RequestError("Too many failed logins, try again later"
+                        .to_owned())
+                    .into());
+            }
+            if pbkdf2_check(password, hash).is_err() {
+                failures.count += 1;
+                if failures.count >= MAX_FAILURES {
+                    failures.count = 0;
+                    failures.locked_until = Some(now + Duration::seconds(LOCKOUT_SECS));
+                }
+                return Err(ErrorKind::RequestError("Wrong password".to_owned()).into());
+            }
+            failures.count = 0;
+        }
+        let bytes: [u8; 16] = thread_rng().gen();
+        let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let mut sessions = self.sessions.lock().unwrap();
+        let live: BTreeMap<String, DateTime<Utc>> = sessions.iter()
+            .filter(|&(_, expiry)| *expiry > now)
+            .map(|(t, e)| (t.clone(), *e))
+            .collect();
+        *sessions = live;
+        sessions.insert(token.clone(), now + Duration::days(SESSION_DAYS));
+        Ok(token)
+    }
+
+    pub fn logout(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    pub fn is_valid(&self, token: &str, now: DateTime<Utc>) -> bool {
+        self.sessions.lock().unwrap().get(token).map(|expiry| *expiry > now).unwrap_or(false)
+    }
+}
+
+/// `Set-Cookie` value for the session. `SameSite=Strict` keeps other sites
+/// from making the browser post to the API with it. An empty token with a
+/// zero `max_age_secs` clears the cookie.
+pub fn session_cookie(token: &str, max_age_secs: i64) -> String {
+    format!("{}={}; Path=/; HttpOnly; SameSite=Strict; Max-Age={}",
+            SESSION_COOKIE, token, max_age_secs)
+}
+
+/// The session token from a bearer `Authorization` header or the session
+/// cookie.
+pub fn request_token(req: &Request) -> Option<String> {
+    if let Some(&Authorization(Bearer { ref token })) = req.headers.get::<Authorization<Bearer>>() {
+        return Some(token.clone());
+    }
+    req.headers.get::<Cookie>().and_then(|&Cookie(ref cookies)| {
+        cookies.iter()
+            .filter_map(|c| {
+                let mut parts = c.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(name), Some(value)) if name.trim() == SESSION_COOKIE => {
+                        Some(value.trim().to_owned())
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+    })
+}
+
+pub struct AuthMiddleware {
+    auth: AuthWrapped,
+}
+
+impl AuthMiddleware {
+    pub fn new(auth: AuthWrapped) -> AuthMiddleware {
+        AuthMiddleware { auth }
+    }
+}
+
+impl BeforeMiddleware for AuthMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        if !self.auth.is_enabled() {
+            return Ok(());
+        }
+        let path = req.url.path().join("/");
+        if PUBLIC_PATHS.contains(&path.as_ref()) {
+            return Ok(());
+        }
+        let now = Utc::now();
+        if request_token(req).map(|t| self.auth.is_valid(&t, now)).unwrap_or(false) {
+            Ok(())
+        } else {
+            Err(IronError::new(ErrorKind::RequestError("Login required".to_owned()),
+                               status::Unauthorized))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Utc, TimeZone, Duration};
+    use auth::{Auth, hash_password, session_cookie};
+
+    #[test]
+    fn login_sessions() {
+        let auth = Auth::new(Some(hash_password("secret").unwrap()));
+        let now = Utc.ymd(2017, 2, 1).and_hms(10, 0, 0);
+        assert!(auth.login("guess", now).is_err());
+        let token = auth.login("secret", now).unwrap();
+        assert!(auth.is_valid(&token, now));
+        assert!(!auth.is_valid("guess", now));
+        assert!(!auth.is_valid(&token, now + Duration::days(31)));
+        auth.logout(&token);
+        assert!(!auth.is_valid(&token, now));
+
+        assert!(Auth::new(None).login("secret", now).is_err());
+    }
+
+    #[test]
+    fn login_lockout() {
+        let auth = Auth::new(Some(hash_password("secret").unwrap()));
+        let now = Utc.ymd(2017, 2, 1).and_hms(10, 0, 0);
+        for _ in 0..4 {
+            assert!(auth.login("guess", now).is_err());
+        }
+        // A success starts the count over.
+        auth.login("secret", now).unwrap();
+        for _ in 0..5 {
+            assert!(auth.login("guess", now).is_err());
+        }
+        assert!(auth.login("secret", now + Duration::seconds(59)).is_err());
+        auth.login("secret", now + Duration::seconds(60)).unwrap();
+    }
+
+    #[test]
+    fn cookie_same_site() {
+        assert_eq!("device_blocker_session=abc; Path=/; HttpOnly; SameSite=Strict; Max-Age=60",
+                   session_cookie("abc", 60));
+    }
+}
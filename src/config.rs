@@ -1,6 +1,24 @@
-use std::collections::BTreeSet;
-pub use ::types::Config;
-use schedule::{World, Device};
+use std::collections::{BTreeSet, BTreeMap};
+pub use ::types::{Config, Firewall, LeaseFormat, UnknownPolicy};
+use schedule::{World, Device, ScheduleEntry, PendingEntry, MacAddress};
+use errors::Result;
+
+/// Rejects settings the server cannot run with.
+pub fn check_config(config: &Config) -> Result<()> {
+    // The scheduler would wake up without pause.
+    if config.discovery_interval_secs == Some(0) {
+        return Err("discovery_interval_secs must be positive, leave it unset to turn it off"
+            .into());
+    }
+    if config.drift_check_secs == Some(0) {
+        return Err("drift_check_secs must be positive, leave it unset to turn it off".into());
+    }
+    let known_macs: BTreeSet<&MacAddress> = config.known_devices.iter().map(|d| &d.mac).collect();
+    if let Some(dev) = config.ignored_devices.iter().find(|d| known_macs.contains(&d.mac)) {
+        return Err(format!("{} is both a known and an ignored device", dev.mac.as_str()).into());
+    }
+    Ok(())
+}
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct ReconcileResult {
@@ -9,6 +27,7 @@ pub struct ReconcileResult {
 
 pub fn reconcile_config(config: &Config,
                         dhcp_devs: &BTreeSet<Device>,
+                        neighbor_macs: &BTreeSet<MacAddress>,
                         world: &mut World)
                         -> ReconcileResult {
     let mut config_set: BTreeSet<Device> = BTreeSet::new();
@@ -27,91 +46,159 @@ pub fn reconcile_config(config: &Config,
     let config_set = config_set;
     let world_set = world_set;
 
+    // Devices are matched by MAC so a renamed device stays open or closed.
+    let config_by_mac: BTreeMap<&MacAddress, &Device> =
+        config_set.iter().map(|d| (&d.mac, d)).collect();
+    let world_macs: BTreeSet<&MacAddress> = world_set.iter().map(|d| &d.mac).collect();
+
     world.closed_devices = world.closed_devices
-        .clone()
-        .into_iter()
-        .filter(|d| config_set.contains(d))
+        .iter()
+        .filter_map(|d| config_by_mac.get(&d.mac).map(|c| (*c).clone()))
         .collect();
 
     world.schedule.open_device_entries = world.schedule
         .open_device_entries
-        .clone()
-        .into_iter()
-        .filter(|e| config_set.contains(&e.item))
+        .iter()
+        .filter_map(|e| {
+            config_by_mac.get(&e.item.mac).map(|c| {
+                ScheduleEntry {
+                    item: (*c).clone(),
+                    time_bound: e.time_bound,
+                }
+            })
+        })
+        .collect();
+    world.schedule.pending_device_entries = world.schedule
+        .pending_device_entries
+        .iter()
+        .filter_map(|e| {
+            config_by_mac.get(&e.item.mac).map(|c| {
+                PendingEntry {
+                    item: (*c).clone(),
+                    time_bound: e.time_bound,
+                    start: e.start,
+                }
+            })
+        })
         .collect();
 
-    let new_device_iter = config_set.difference(&world_set);
-    for new_device in new_device_iter {
+    for new_device in config_set.iter().filter(|d| !world_macs.contains(&d.mac)) {
         world.closed_devices.insert(new_device.clone());
     }
 
-    let known_set: BTreeSet<String> = world.closed_devices
+    let known_set: BTreeSet<&MacAddress> = world.closed_devices
         .iter()
         .chain(world.schedule.open_device_entries.iter().map(|e| &e.item))
-        .map(|d| d.mac.to_uppercase())
+        .chain(config.ignored_devices.iter())
+        .map(|d| &d.mac)
         .collect();
+    // Neighbors without a lease have no hostname to go by.
+    let dhcp_macs: BTreeSet<&MacAddress> = dhcp_devs.iter().map(|d| &d.mac).collect();
+    let neighbor_devs = neighbor_macs.iter()
+        .filter(|mac| !dhcp_macs.contains(mac))
+        .map(|mac| {
+            Device {
+                name: "*".to_owned(),
+                mac: mac.clone(),
+                daily_quota_mins: None,
+            }
+        });
     let unknown_devs = dhcp_devs.iter()
-        .filter(|d| !known_set.contains(&d.mac.to_uppercase()))
         .cloned()
+        .chain(neighbor_devs)
+        .filter(|d| !known_set.contains(&d.mac))
         .collect();
     world.unknown_devices = unknown_devs;
 
-    ReconcileResult { updated_world: !(config_set == world_set) }
+    let groups_changed = world.groups != config.groups;
+    world.groups = config.groups.clone();
+    let ignored_changed = world.ignored_devices != config.ignored_devices;
+    world.ignored_devices = config.ignored_devices.clone();
+
+    ReconcileResult {
+        updated_world: !(config_set == world_set) || groups_changed || ignored_changed,
+    }
 }
 
 #[cfg(test)]
-mod test {
-    use std::collections::BTreeSet;
+pub mod test {
+    use std::collections::{BTreeSet, BTreeMap};
     use schedule::test::world_fixture;
-    use config::{reconcile_config, Config, ReconcileResult};
-    use schedule::{Device, ScheduleEntry};
+    use config::{reconcile_config, check_config, Config, Firewall, LeaseFormat,
+                 ReconcileResult, UnknownPolicy};
+    use schedule::{Device, ScheduleEntry, MacAddress};
+    use mac::test::mac;
+    use chrono::NaiveTime;
 
-    fn unknown_devs_fixture() -> BTreeSet<Device> {
+    pub fn unknown_devs_fixture() -> BTreeSet<Device> {
         let mut devs = BTreeSet::new();
         devs.insert(Device {
             name: "TV2".to_owned(),
-            mac: "1234".to_owned(),
+            mac: mac("00:00:00:00:12:34"),
+            daily_quota_mins: None,
         });
         // Has different case than in world.
         devs.insert(Device {
             name: "TV3".to_owned(),
-            mac: "bBbB".to_owned(),
+            mac: mac("00-00-00-00-BB-BB"),
+            daily_quota_mins: None,
         });
         devs.insert(Device {
             name: "TV20".to_owned(),
-            mac: "2020".to_owned(),
+            mac: mac("00:00:00:00:20:20"),
+            daily_quota_mins: None,
         });
         devs.insert(Device {
             name: "TV21".to_owned(),
-            mac: "2121".to_owned(),
+            mac: mac("00:00:00:00:21:21"),
+            daily_quota_mins: None,
         });
         devs
     }
 
-    fn config_fixture() -> Config {
+    pub fn config_fixture() -> Config {
         Config {
             exit_interfaces: BTreeSet::new(),
             dhcp_lease_file: "".to_owned(),
+            lease_format: LeaseFormat::Dnsmasq,
+            arp_file: None,
+            ip6_neighbor_command: None,
             state_file: "".to_owned(),
             known_devices: [Device {
                                 name: "TV1".to_owned(),
-                                mac: "5678".to_owned(),
+                                mac: mac("00:00:00:00:56:78"),
+                                daily_quota_mins: None,
                             },
                             Device {
                                 name: "TV2".to_owned(),
-                                mac: "1234".to_owned(),
+                                mac: mac("00:00:00:00:12:34"),
+                                daily_quota_mins: None,
                             },
                             Device {
                                 name: "TV3".to_owned(),
-                                mac: "bbbb".to_owned(),
+                                mac: mac("00:00:00:00:bb:bb"),
+                                daily_quota_mins: None,
                             },
                             Device {
                                 name: "TV4".to_owned(),
-                                mac: "abcd".to_owned(),
+                                mac: mac("00:00:00:00:ab:cd"),
+                                daily_quota_mins: None,
                             }]
                 .iter()
                 .cloned()
                 .collect(),
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            quota_reset_time: NaiveTime::from_hms(0, 0, 0),
+            firewall: Firewall::Iptables,
+            unknown_device_policy: UnknownPolicy::Guest,
+            admin_password_hash: None,
+            history_file: None,
+            history_max_bytes: 0,
+            discovery_interval_secs: None,
+            drift_check_secs: None,
+            new_device_webhook: None,
+            new_device_command: None,
         }
     }
 
@@ -123,7 +210,7 @@ mod test {
 
         let expected_world = world.clone();
 
-        let result = reconcile_config(&config, &unknown_devs, &mut world);
+        let result = reconcile_config(&config, &unknown_devs, &BTreeSet::new(), &mut world);
         assert_eq!(ReconcileResult { updated_world: false }, result);
         assert_eq!(expected_world, world);
     }
@@ -133,14 +220,16 @@ mod test {
         let mut config = config_fixture();
         config.known_devices.insert(Device {
             name: "TV5".to_owned(),
-            mac: "xyz".to_owned(),
+            mac: mac("00:00:00:00:aa:01"),
+            daily_quota_mins: None,
         });
 
         let mut world = world_fixture();
         world.schedule.open_device_entries.insert(ScheduleEntry {
             item: Device {
                 name: "TV6".to_owned(),
-                mac: "qrs".to_owned(),
+                mac: mac("00:00:00:00:aa:02"),
+                daily_quota_mins: None,
             },
             time_bound: None,
         });
@@ -153,7 +242,8 @@ mod test {
         world.schedule.open_device_entries.remove(&tv2_item);
         world.closed_devices.insert(Device {
             name: "TV7".to_owned(),
-            mac: "tuv".to_owned(),
+            mac: mac("00:00:00:00:aa:03"),
+            daily_quota_mins: None,
         });
 
         let unknown_devs = unknown_devs_fixture();
@@ -168,24 +258,117 @@ mod test {
         expected_world.schedule.open_device_entries.remove(&tv2_item);
         expected_world.closed_devices.insert(Device {
             name: "TV5".to_owned(),
-            mac: "xyz".to_owned(),
+            mac: mac("00:00:00:00:aa:01"),
+            daily_quota_mins: None,
         });
         expected_world.closed_devices.insert(Device {
             name: "TV2".to_owned(),
-            mac: "1234".to_owned(),
+            mac: mac("00:00:00:00:12:34"),
+            daily_quota_mins: None,
         });
         expected_world.unknown_devices.insert(Device {
             name: "TV20".to_owned(),
-            mac: "2020".to_owned(),
+            mac: mac("00:00:00:00:20:20"),
+            daily_quota_mins: None,
         });
         expected_world.unknown_devices.insert(Device {
             name: "TV21".to_owned(),
-            mac: "2121".to_owned(),
+            mac: mac("00:00:00:00:21:21"),
+            daily_quota_mins: None,
         });
 
-        let result = reconcile_config(&config, &unknown_devs, &mut world);
+        let result = reconcile_config(&config, &unknown_devs, &BTreeSet::new(), &mut world);
         assert_eq!(ReconcileResult { updated_world: true }, result);
         println!("{:#?}\n{:#?}", expected_world, world);
         assert_eq!(expected_world, world);
     }
+
+    #[test]
+    fn neighbors_without_lease() {
+        let config = config_fixture();
+        let mut world = world_fixture();
+        let neighbors: BTreeSet<MacAddress> =
+            [mac("00:00:00:00:12:34"), mac("00:00:00:00:20:20"), mac("00:00:00:00:40:40")]
+                .iter()
+                .cloned()
+                .collect();
+
+        reconcile_config(&config, &unknown_devs_fixture(), &neighbors, &mut world);
+        let unknown: Vec<(String, MacAddress)> = world.unknown_devices
+            .iter()
+            .map(|d| (d.name.clone(), d.mac.clone()))
+            .collect();
+        assert_eq!(vec![("*".to_owned(), mac("00:00:00:00:40:40")),
+                        ("TV20".to_owned(), mac("00:00:00:00:20:20")),
+                        ("TV21".to_owned(), mac("00:00:00:00:21:21"))],
+                   unknown);
+    }
+
+    #[test]
+    fn renamed_device_keeps_state() {
+        let mut config = config_fixture();
+        let rename = |config: &mut Config, old: &str, new: &str| {
+            let dev = config.known_devices.iter().find(|d| d.name == old).unwrap().clone();
+            config.known_devices.remove(&dev);
+            config.known_devices.insert(Device { name: new.to_owned(), ..dev });
+        };
+        rename(&mut config, "TV1", "Living room");
+        rename(&mut config, "TV3", "Bedroom");
+        let mut world = world_fixture();
+
+        let result = reconcile_config(&config, &BTreeSet::new(), &BTreeSet::new(), &mut world);
+        assert_eq!(ReconcileResult { updated_world: true }, result);
+        let open: Vec<&str> = world.schedule
+            .open_device_entries
+            .iter()
+            .map(|e| e.item.name.as_ref())
+            .collect();
+        assert_eq!(vec!["Living room", "TV2"], open);
+        let closed: Vec<&str> = world.closed_devices.iter().map(|d| d.name.as_ref()).collect();
+        assert_eq!(vec!["Bedroom", "TV4"], closed);
+
+        let result = reconcile_config(&config, &BTreeSet::new(), &BTreeSet::new(), &mut world);
+        assert_eq!(ReconcileResult { updated_world: false }, result);
+    }
+
+    #[test]
+    fn ignored_devices() {
+        let mut config = config_fixture();
+        let printer = Device {
+            name: "printer".to_owned(),
+            mac: mac("00:00:00:00:20:20"),
+            daily_quota_mins: None,
+        };
+        config.ignored_devices.insert(printer.clone());
+        let mut world = world_fixture();
+
+        let result = reconcile_config(&config, &unknown_devs_fixture(), &BTreeSet::new(),
+                                      &mut world);
+        assert_eq!(ReconcileResult { updated_world: true }, result);
+        assert!(world.ignored_devices.contains(&printer));
+        let unknown: Vec<&str> = world.unknown_devices.iter().map(|d| d.name.as_ref()).collect();
+        assert_eq!(vec!["TV21"], unknown);
+    }
+
+    #[test]
+    fn zero_intervals() {
+        let mut config = config_fixture();
+        check_config(&config).unwrap();
+        config.discovery_interval_secs = Some(0);
+        assert!(check_config(&config).is_err());
+        config.discovery_interval_secs = None;
+        config.drift_check_secs = Some(0);
+        assert!(check_config(&config).is_err());
+    }
+
+    #[test]
+    fn known_and_ignored() {
+        let mut config = config_fixture();
+        config.ignored_devices.insert(Device {
+            name: "TV".to_owned(),
+            mac: mac("00:00:00:00:12:34"),
+            daily_quota_mins: None,
+        });
+        assert!(check_config(&config).is_err());
+    }
 }
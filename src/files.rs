@@ -1,15 +1,43 @@
 use serde::de::DeserializeOwned;
 use serde::ser::Serialize;
 use serde_json;
-use std::fs::File;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
 
 use errors::{Result, ResultExt};
 
+fn backup_name(file_name: &str) -> String {
+    format!("{}.bak", file_name)
+}
+
+/// Writes `obj` to a temporary file and renames it over `file_name`, so a
+/// crash leaves either the old or the new contents. The previous contents
+/// are kept in `file_name.bak`.
 pub fn write_json_file<T: Serialize>(file_name: &str, obj: &T) -> Result<()> {
-    let mut writer =
-        File::create(file_name).chain_err(|| format!("Failed to open {} for writing", file_name))?;
-    serde_json::to_writer_pretty(&mut writer, obj)
-        .chain_err(|| format!("Failed to write json file {}", file_name))
+    let tmp_name = format!("{}.tmp", file_name);
+    {
+        let mut writer = File::create(&tmp_name)
+            .chain_err(|| format!("Failed to open {} for writing", tmp_name))?;
+        serde_json::to_writer_pretty(&mut writer, obj)
+            .chain_err(|| format!("Failed to write json file {}", tmp_name))?;
+        writer.flush()
+            .and_then(|_| writer.sync_all())
+            .chain_err(|| format!("Failed to sync {}", tmp_name))?;
+    }
+    if Path::new(file_name).exists() {
+        fs::copy(file_name, backup_name(file_name))
+            .chain_err(|| format!("Failed to back up {}", file_name))?;
+    }
+    fs::rename(&tmp_name, file_name)
+        .chain_err(|| format!("Failed to rename {} to {}", tmp_name, file_name))?;
+    let dir = match Path::new(file_name).parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|d| d.sync_all())
+        .chain_err(|| format!("Failed to sync directory of {}", file_name))
 }
 
 pub fn read_json_file<T: DeserializeOwned>(file_name: &str) -> Result<T> {
@@ -18,3 +46,60 @@ pub fn read_json_file<T: DeserializeOwned>(file_name: &str) -> Result<T> {
             .chain_err(|| format!("Failed to open {}", file_name))?)
         .chain_err(|| format!("Failed to read json file {}", file_name))
 }
+
+/// Reads `file_name`, falling back to the backup left by `write_json_file`
+/// when the primary is missing or unparsable.
+pub fn read_json_file_or_backup<T: DeserializeOwned>(file_name: &str) -> Result<T> {
+    read_json_file(file_name).or_else(|err| {
+        read_json_file(&backup_name(file_name)).map_err(|_| err)
+    })
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use files::{write_json_file, read_json_file, read_json_file_or_backup};
+
+    /// A fresh directory for a test's files, removed when dropped so a
+    /// failing test does not leave it behind.
+    pub struct TempDir(PathBuf);
+
+    impl TempDir {
+        pub fn new(name: &str) -> TempDir {
+            let dir = env::temp_dir()
+                .join(format!("device-blocker-{}-{}", name, ::std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+
+        pub fn path(&self, file: &str) -> String {
+            self.0.join(file).to_str().unwrap().to_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn falls_back_to_backup() {
+        let dir = TempDir::new("files");
+        let file_name = dir.path("state.json");
+
+        write_json_file(&file_name, &vec![1]).unwrap();
+        write_json_file(&file_name, &vec![2]).unwrap();
+        assert_eq!(vec![2], read_json_file::<Vec<i32>>(&file_name).unwrap());
+        assert_eq!(vec![1],
+                   read_json_file::<Vec<i32>>(&format!("{}.bak", file_name)).unwrap());
+
+        File::create(&file_name).unwrap().write_all(b"[2,").unwrap();
+        assert!(read_json_file::<Vec<i32>>(&file_name).is_err());
+        assert_eq!(vec![1], read_json_file_or_backup::<Vec<i32>>(&file_name).unwrap());
+    }
+}
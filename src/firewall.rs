@@ -0,0 +1,501 @@
+use std::cmp::min;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+use chrono::{DateTime, Utc, Duration};
+use config::Firewall;
+use script::{Rule, write_restore_payload, check_saved_rules, check_nft_sets, nft_commands};
+use script_handler::{ScriptHandler, HandleScript, ScriptCommand};
+
+use errors::{Result, ResultExt};
+
+/// Enforces the rules built from a `World` on the router.
+pub trait FirewallBackend: Send + Sync {
+    fn apply(&self, rules: &[Rule]) -> Result<()>;
+
+    /// Whether the live firewall still holds `rules`, which something else
+    /// reloading the firewall may have removed. This runs commands, so it is
+    /// called without the app lock held.
+    fn in_place(&self, _rules: &[Rule]) -> Result<bool> {
+        Ok(true)
+    }
+}
+
+const CHAIN: &'static str = "blocked_devices";
+const OLD_CHAIN: &'static str = "old_blocked_devices";
+const TABLE: &'static str = "device_blocker";
+
+pub struct IptablesBackend {
+    pub handler: ScriptHandler,
+}
+
+impl IptablesBackend {
+    fn apply_family(&self, rules: &[Rule], save: &str, restore: &str) -> Result<()> {
+        let save_command = ScriptCommand::new(&[save, "-t", "filter"]);
+        let saved = self.handler.read(&save_command)?.unwrap_or_default();
+        let mut payload = String::new();
+        write_restore_payload(rules, CHAIN, OLD_CHAIN, &saved, &mut payload)?;
+        self.handler.handle(&[ScriptCommand::new(&[restore, "--noflush"]).with_stdin(payload)])?;
+        if let Some(saved) = self.handler.read(&save_command)? {
+            check_saved_rules(rules, CHAIN, &saved)
+                .chain_err(|| format!("{} did not take effect", restore))?;
+        }
+        Ok(())
+    }
+}
+
+impl FirewallBackend for IptablesBackend {
+    fn apply(&self, rules: &[Rule]) -> Result<()> {
+        self.apply_family(rules, "iptables-save", "iptables-restore")?;
+        self.apply_family(rules, "ip6tables-save", "ip6tables-restore")
+    }
+
+    fn in_place(&self, rules: &[Rule]) -> Result<bool> {
+        for program in &["iptables", "ip6tables"] {
+            if let Some(listed) = self.handler.read(&ScriptCommand::new(&[program, "-S"]))? {
+                if check_saved_rules(rules, CHAIN, &listed).is_err() {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+}
+
+pub struct NftablesBackend {
+    pub handler: ScriptHandler,
+}
+
+impl FirewallBackend for NftablesBackend {
+    fn apply(&self, rules: &[Rule]) -> Result<()> {
+        self.handler.handle(&nft_commands(rules, TABLE)?)
+    }
+
+    fn in_place(&self, rules: &[Rule]) -> Result<bool> {
+        // Listing a missing table is an error, so look for it first.
+        let tables = ScriptCommand::new(&["nft", "list", "tables", "inet"]);
+        let declaration = format!("table inet {}", TABLE);
+        match self.handler.read(&tables)? {
+            Some(ref listed) if !listed.lines().any(|l| l.trim() == declaration) => {
+                return Ok(false);
+            }
+            Some(_) => {}
+            None => return Ok(true),
+        }
+        let list = ScriptCommand::new(&["nft", "list", "table", "inet", TABLE]);
+        Ok(match self.handler.read(&list)? {
+            Some(listed) => check_nft_sets(rules, &listed).is_ok(),
+            None => true,
+        })
+    }
+}
+
+/// Leaves the firewall alone.
+pub struct NoopBackend;
+
+impl FirewallBackend for NoopBackend {
+    fn apply(&self, _rules: &[Rule]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Leaves the firewall alone and keeps every ruleset it was asked to apply.
+#[cfg(test)]
+#[derive(Default)]
+pub struct RecordingBackend {
+    pub applied: Arc<Mutex<Vec<Vec<Rule>>>>,
+}
+
+#[cfg(test)]
+impl FirewallBackend for RecordingBackend {
+    fn apply(&self, rules: &[Rule]) -> Result<()> {
+        self.applied.lock().unwrap().push(rules.to_vec());
+        Ok(())
+    }
+}
+
+/// Whether the kernel holds the rules built from the current `World`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, GraphQLObject)]
+pub struct FirewallStatus {
+    pub in_sync: bool,
+    pub desired: Vec<String>,
+    /// The last ruleset which applied cleanly.
+    pub applied: Vec<String>,
+    pub last_applied: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub failures: i32,
+    pub next_retry: Option<DateTime<Utc>>,
+    /// Times the rules were found missing from the live firewall and put back.
+    pub drift_repairs: i32,
+    pub last_drift: Option<DateTime<Utc>>,
+}
+
+const MAX_RETRY_SECS: i64 = 600;
+
+/// A look at whether the live firewall still holds the applied rules, taken
+/// apart from the `RuleApplier` so it can run without the app lock held.
+pub struct DriftCheck {
+    backend: Arc<FirewallBackend>,
+    applied: Vec<Rule>,
+}
+
+impl DriftCheck {
+    pub fn in_place(&self) -> Result<bool> {
+        self.backend.in_place(&self.applied)
+    }
+}
+
+/// Applies rules through a backend. When a ruleset fails the last one which
+/// applied cleanly is put back, and the failed one is retried with backoff.
+pub struct RuleApplier {
+    backend: Arc<FirewallBackend>,
+    desired: Vec<Rule>,
+    applied: Option<Vec<Rule>>,
+    last_attempt: Option<DateTime<Utc>>,
+    last_applied: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    failures: i32,
+    drift_repairs: i32,
+    last_drift: Option<DateTime<Utc>>,
+}
+
+impl RuleApplier {
+    pub fn new(backend: Box<FirewallBackend>) -> RuleApplier {
+        RuleApplier {
+            backend: Arc::from(backend),
+            desired: vec![],
+            applied: None,
+            last_attempt: None,
+            last_applied: None,
+            last_error: None,
+            failures: 0,
+            drift_repairs: 0,
+            last_drift: None,
+        }
+    }
+
+    pub fn apply(&mut self, rules: Vec<Rule>, now: DateTime<Utc>) -> Result<()> {
+        self.desired = rules;
+        self.last_attempt = Some(now);
+        match self.backend.apply(&self.desired) {
+            Ok(()) => {
+                self.applied = Some(self.desired.clone());
+                self.last_applied = Some(now);
+                self.last_error = None;
+                self.failures = 0;
+                Ok(())
+            }
+            Err(err) => {
+                self.failures += 1;
+                self.last_error = Some(err.to_string());
+                if let Some(ref applied) = self.applied {
+                    self.backend
+                        .apply(applied)
+                        .unwrap_or_else(|err| println!("Failed to restore rules: {:?}", err));
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// The check for `repair_drift`, or None when nothing has applied yet or
+    /// a failed ruleset is left to the retry.
+    pub fn drift_check(&self) -> Option<DriftCheck> {
+        match self.applied {
+            Some(ref applied) if self.failures == 0 => {
+                Some(DriftCheck {
+                    backend: self.backend.clone(),
+                    applied: applied.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+
+    /// Applies the rules again when `check` found them missing from the live
+    /// firewall. Returns whether they had drifted. Nothing is done if other
+    /// rules were applied since the check was taken.
+    pub fn repair_drift(&mut self, check: &DriftCheck, in_place: bool, now: DateTime<Utc>)
+                        -> Result<bool> {
+        if in_place || self.failures != 0 || self.applied.as_ref() != Some(&check.applied) {
+            return Ok(false);
+        }
+        self.drift_repairs += 1;
+        self.last_drift = Some(now);
+        let desired = self.desired.clone();
+        self.apply(desired, now)?;
+        Ok(true)
+    }
+
+    /// 5 seconds after the first failure, doubling up to 10 minutes.
+    pub fn next_retry(&self) -> Option<DateTime<Utc>> {
+        if self.failures == 0 {
+            return None;
+        }
+        let doublings = min(self.failures - 1, 8);
+        let secs = min(5i64 << doublings, MAX_RETRY_SECS);
+        self.last_attempt.map(|t| t + Duration::seconds(secs))
+    }
+
+    pub fn status(&self) -> FirewallStatus {
+        let to_strings = |rules: &[Rule]| rules.iter().map(|r| r.to_string()).collect();
+        FirewallStatus {
+            in_sync: self.failures == 0 && self.applied.is_some(),
+            desired: to_strings(&self.desired),
+            applied: self.applied.as_ref().map(|a| to_strings(a)).unwrap_or_default(),
+            last_applied: self.last_applied,
+            last_error: self.last_error.clone(),
+            failures: self.failures,
+            next_retry: self.next_retry(),
+            drift_repairs: self.drift_repairs,
+            last_drift: self.last_drift,
+        }
+    }
+}
+
+pub fn new_backend(firewall: Firewall, handler: ScriptHandler) -> Box<FirewallBackend> {
+    match firewall {
+        Firewall::Iptables => Box::new(IptablesBackend { handler }),
+        Firewall::Nftables => Box::new(NftablesBackend { handler }),
+        Firewall::Noop => Box::new(NoopBackend),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+    use schedule::test::world_fixture;
+    use schedule::{World, GuestPath, DeviceOverride, ScheduleEntry, Device, UnknownPolicy};
+    use script::{Rule, Action, build_rules};
+    use std::sync::{Arc, Mutex};
+    use firewall::{FirewallBackend, RecordingBackend, RuleApplier};
+    use errors::Result;
+    use mac::test::mac;
+    use chrono::{DateTime, Utc, TimeZone, Duration};
+
+    #[test]
+    fn rules_follow_world() {
+        let mut world = world_fixture();
+        let mut interfaces = BTreeSet::new();
+        interfaces.insert("eth0".to_owned());
+        let backend = RecordingBackend::default();
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+
+        backend.apply(&build_rules(&world, &interfaces, UnknownPolicy::Guest, now)).unwrap();
+        world.schedule.override_entry = None;
+        backend.apply(&build_rules(&world, &interfaces, UnknownPolicy::Guest, now)).unwrap();
+        world.schedule.guest_entry.item = GuestPath::Open;
+        world.open_device(&mac("00:00:00:00:bb:bb"), None).unwrap();
+        backend.apply(&build_rules(&world, &interfaces, UnknownPolicy::Guest, now)).unwrap();
+        world.schedule.override_entry = Some(ScheduleEntry {
+            item: DeviceOverride::Open,
+            time_bound: None,
+        });
+        backend.apply(&build_rules(&world, &interfaces, UnknownPolicy::Guest, now)).unwrap();
+
+        let applied = backend.applied.lock().unwrap();
+        assert_eq!(vec![Rule::AcceptInterface("eth0".to_owned()),
+                        Rule::Mac(mac("00:00:00:00:56:78"), Action::Drop),
+                        Rule::Mac(mac("00:00:00:00:12:34"), Action::Drop),
+                        Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Drop),
+                        Rule::Mac(mac("00:00:00:00:ab:cd"), Action::Drop),
+                        Rule::DropAll],
+                   applied[0]);
+        assert_eq!(vec![Rule::AcceptInterface("eth0".to_owned()),
+                        Rule::Mac(mac("00:00:00:00:56:78"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:12:34"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Drop),
+                        Rule::Mac(mac("00:00:00:00:ab:cd"), Action::Drop),
+                        Rule::DropAll],
+                   applied[1]);
+        assert_eq!(vec![Rule::AcceptInterface("eth0".to_owned()),
+                        Rule::Mac(mac("00:00:00:00:56:78"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:12:34"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:ab:cd"), Action::Drop)],
+                   applied[2]);
+        assert_eq!(vec![Rule::AcceptInterface("eth0".to_owned()),
+                        Rule::Mac(mac("00:00:00:00:56:78"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:12:34"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Accept),
+                        Rule::Mac(mac("00:00:00:00:ab:cd"), Action::Accept)],
+                   applied[3]);
+    }
+
+    fn unknown_rule(world: &World, policy: UnknownPolicy, now: DateTime<Utc>) -> Option<Rule> {
+        build_rules(world, &BTreeSet::new(), policy, now).into_iter().find(|r| match *r {
+            Rule::Mac(ref m, _) => *m == mac("00:00:00:00:ee:ee"),
+            _ => false,
+        })
+    }
+
+    #[test]
+    fn unknown_device_policy() {
+        let mut world = world_fixture();
+        world.schedule.override_entry = None;
+        world.unknown_devices.insert(Device {
+            name: "*".to_owned(),
+            mac: mac("00:00:00:00:ee:ee"),
+            daily_quota_mins: None,
+        });
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let later = now + Duration::minutes(30);
+        let accept = Some(Rule::Mac(mac("00:00:00:00:ee:ee"), Action::Accept));
+        let drop = Some(Rule::Mac(mac("00:00:00:00:ee:ee"), Action::Drop));
+
+        assert_eq!(None, unknown_rule(&world, UnknownPolicy::Guest, now));
+        assert_eq!(accept, unknown_rule(&world, UnknownPolicy::Allow, now));
+        assert_eq!(drop, unknown_rule(&world, UnknownPolicy::Block, now));
+        // Not seen yet, so the quarantine has not started.
+        assert_eq!(accept, unknown_rule(&world, UnknownPolicy::Quarantine(30), later));
+
+        world.record_seen_devices(now);
+        assert_eq!(accept, unknown_rule(&world, UnknownPolicy::Quarantine(30), now));
+        assert_eq!(drop, unknown_rule(&world, UnknownPolicy::Quarantine(30), later));
+        assert_eq!(Some(later),
+                   world.get_next_quarantine_time(UnknownPolicy::Quarantine(30), now));
+        assert_eq!(None, world.get_next_quarantine_time(UnknownPolicy::Quarantine(30), later));
+    }
+
+    #[test]
+    fn ignored_devices_always_accepted() {
+        let mut world = world_fixture();
+        world.ignored_devices.insert(Device {
+            name: "printer".to_owned(),
+            mac: mac("00:00:00:00:20:20"),
+            daily_quota_mins: None,
+        });
+        let rules = build_rules(&world, &BTreeSet::new(), UnknownPolicy::Block,
+                                Utc.ymd(2018, 3, 1).and_hms(10, 0, 0));
+        assert_eq!(Rule::Mac(mac("00:00:00:00:20:20"), Action::Accept), rules[0]);
+        assert_eq!(Some(&Rule::DropAll), rules.last());
+    }
+
+    #[test]
+    fn known_device_ignored_too_stays_closed() {
+        let mut world = world_fixture();
+        world.schedule.override_entry = None;
+        world.ignored_devices.insert(Device {
+            name: "TV".to_owned(),
+            mac: mac("00:00:00:00:bb:bb"),
+            daily_quota_mins: None,
+        });
+        let rules = build_rules(&world, &BTreeSet::new(), UnknownPolicy::Block,
+                                Utc.ymd(2018, 3, 1).and_hms(10, 0, 0));
+        assert!(rules.contains(&Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Drop)));
+        assert!(!rules.contains(&Rule::Mac(mac("00:00:00:00:bb:bb"), Action::Accept)));
+    }
+
+    struct FlakyBackend {
+        failing: Arc<Mutex<bool>>,
+        recording: RecordingBackend,
+    }
+
+    impl FirewallBackend for FlakyBackend {
+        fn apply(&self, rules: &[Rule]) -> Result<()> {
+            if *self.failing.lock().unwrap() && rules.contains(&Rule::DropAll) {
+                return Err("iptables-restore failed".into());
+            }
+            self.recording.apply(rules)
+        }
+    }
+
+    #[test]
+    fn restore_and_retry() {
+        let failing = Arc::new(Mutex::new(false));
+        let recording = RecordingBackend::default();
+        let applied = recording.applied.clone();
+        let mut applier = RuleApplier::new(Box::new(FlakyBackend {
+            failing: failing.clone(),
+            recording,
+        }));
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let good = vec![Rule::Mac(mac("00:00:00:00:12:34"), Action::Accept)];
+        let bad = vec![Rule::Mac(mac("00:00:00:00:12:34"), Action::Drop), Rule::DropAll];
+
+        applier.apply(good.clone(), now).unwrap();
+        assert!(applier.status().in_sync);
+        assert_eq!(None, applier.next_retry());
+
+        *failing.lock().unwrap() = true;
+        assert!(applier.apply(bad.clone(), now).is_err());
+        assert!(applier.apply(bad.clone(), now).is_err());
+        let status = applier.status();
+        assert!(!status.in_sync);
+        assert_eq!(vec!["accept 00:00:00:00:12:34".to_owned()], status.applied);
+        assert_eq!(vec!["drop 00:00:00:00:12:34".to_owned(), "drop all".to_owned()],
+                   status.desired);
+        assert_eq!(Some(now + Duration::seconds(10)), status.next_retry);
+        // The good rules went back in after each failure.
+        assert_eq!(vec![good.clone(), good.clone(), good.clone()], *applied.lock().unwrap());
+
+        for _ in 0..10 {
+            let _ = applier.apply(bad.clone(), now);
+        }
+        assert_eq!(Some(now + Duration::seconds(600)), applier.next_retry());
+
+        *failing.lock().unwrap() = false;
+        applier.apply(bad.clone(), now).unwrap();
+        assert!(applier.status().in_sync);
+        assert_eq!(None, applier.next_retry());
+    }
+
+    struct DriftingBackend {
+        flushed: Arc<Mutex<bool>>,
+        recording: RecordingBackend,
+    }
+
+    impl FirewallBackend for DriftingBackend {
+        fn apply(&self, rules: &[Rule]) -> Result<()> {
+            *self.flushed.lock().unwrap() = false;
+            self.recording.apply(rules)
+        }
+
+        fn in_place(&self, _rules: &[Rule]) -> Result<bool> {
+            Ok(!*self.flushed.lock().unwrap())
+        }
+    }
+
+    #[test]
+    fn drift_repaired() {
+        let flushed = Arc::new(Mutex::new(false));
+        let recording = RecordingBackend::default();
+        let applied = recording.applied.clone();
+        let mut applier = RuleApplier::new(Box::new(DriftingBackend {
+            flushed: flushed.clone(),
+            recording,
+        }));
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let rules = vec![Rule::Mac(mac("00:00:00:00:12:34"), Action::Drop), Rule::DropAll];
+
+        let repair = |applier: &mut RuleApplier, now| {
+            let check = applier.drift_check().unwrap();
+            let in_place = check.in_place().unwrap();
+            applier.repair_drift(&check, in_place, now).unwrap()
+        };
+
+        // Nothing applied yet, so there is nothing to drift from.
+        *flushed.lock().unwrap() = true;
+        assert!(applier.drift_check().is_none());
+        applier.apply(rules.clone(), now).unwrap();
+        assert!(!repair(&mut applier, now));
+
+        // Rules applied after the check was taken are not checked by it.
+        *flushed.lock().unwrap() = true;
+        let stale = applier.drift_check().unwrap();
+        applier.apply(rules[1..].to_vec(), now).unwrap();
+        assert!(!applier.repair_drift(&stale, false, now).unwrap());
+        applier.apply(rules.clone(), now).unwrap();
+
+        *flushed.lock().unwrap() = true;
+        let later = now + Duration::minutes(1);
+        assert!(repair(&mut applier, later));
+        assert!(!*flushed.lock().unwrap());
+        let status = applier.status();
+        assert_eq!(1, status.drift_repairs);
+        assert_eq!(Some(later), status.last_drift);
+        assert!(status.in_sync);
+        assert_eq!(vec![rules.clone(), rules[1..].to_vec(), rules.clone(), rules.clone()],
+                   *applied.lock().unwrap());
+    }
+}
@@ -6,14 +6,20 @@ use iron::status;
 use iron::method;
 use iron::mime::Mime;
 use iron::Error;
-use juniper::{RootNode, InputValue};
+use juniper::{RootNode, InputValue, FieldResult};
 use juniper::http;
 use serde_json;
 use urlencoded::{UrlEncodedQuery};
 
-use app_server::AppServerSchedulerWrapped;
-use types::World;
-use errors::ErrorKind;
+use chrono::{DateTime, Utc};
+
+use app_server::{AppServer, AppServerSchedulerWrapped, Scheduler, Change, require_mac};
+use types::{World, DeviceOverride, GuestPath, MacAddress};
+use schedule::time_after;
+use script::RuleDiff;
+use history::{Event, EventFilter};
+use firewall::FirewallStatus;
+use errors::{Result, ErrorKind};
 
 impl ::iron::Error for ErrorKind {
     fn description(&self) -> &str {
@@ -23,6 +29,17 @@ impl ::iron::Error for ErrorKind {
 
 pub struct QueryRoot;
 
+/// The mutation whose effect `preview` works out.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, GraphQLEnum)]
+pub enum ChangeKind {
+    OpenDevice,
+    CloseDevice,
+    OpenGroup,
+    CloseGroup,
+    SetGuestPath,
+    SetDeviceOverride,
+}
+
 graphql_object!(QueryRoot: AppServerSchedulerWrapped |&self| {
     field world(&executor) -> World {
         let app_server_scheduler_wrapped = executor.context();
@@ -30,16 +47,226 @@ graphql_object!(QueryRoot: AppServerSchedulerWrapped |&self| {
             .wrapped_server.lock().unwrap();
         let app_server = guard.deref_mut();
 
-        app_server.world.clone()
-    }
+        app_server.current_world()
+    },
+
+    field firewall_status(&executor) -> FirewallStatus {
+        executor.context().wrapped_server.lock().unwrap().firewall_status()
+    },
+
+    field history(&executor,
+                  from: Option<DateTime<Utc>>,
+                  to: Option<DateTime<Utc>>,
+                  mac: Option<String>)
+            -> FieldResult<Vec<Event>> {
+        let mac = match mac {
+            Some(m) => Some(m.parse::<MacAddress>()?),
+            None => None,
+        };
+        let guard = executor.context().wrapped_server.lock().unwrap();
+        let filter = EventFilter { from, to, mac };
+        Ok(guard.history(&filter)?)
+    },
+
+    field preview(&executor,
+                  change: ChangeKind,
+                  mac: Option<String>,
+                  name: Option<String>,
+                  open: Option<bool>,
+                  device_override: Option<DeviceOverride>,
+                  duration_secs: Option<i32>)
+            -> FieldResult<RuleDiff> {
+        let guard = executor.context().wrapped_server.lock().unwrap();
+        let time_bound = time_bound(duration_secs, Utc::now())?;
+        let mac = mac.as_ref().map(|m| m.as_ref());
+        let name = name.as_ref().map(|n| n.as_ref());
+        let change = match change {
+            ChangeKind::OpenDevice => Change::OpenDevice(require_mac(mac)?, time_bound),
+            ChangeKind::CloseDevice => Change::CloseDevice(require_mac(mac)?),
+            ChangeKind::OpenGroup => Change::OpenGroup(guard.group_macs(name)?, time_bound),
+            ChangeKind::CloseGroup => Change::CloseGroup(guard.group_macs(name)?),
+            ChangeKind::SetGuestPath => {
+                let item = if open.ok_or("Missing open argument")? {
+                    GuestPath::Open
+                } else {
+                    GuestPath::Closed
+                };
+                Change::GuestPath(item, time_bound)
+            }
+            ChangeKind::SetDeviceOverride => Change::DeviceOverride(device_override, time_bound),
+        };
+        Ok(guard.preview_change(change)?)
+    },
 });
 
 pub struct MutationRoot;
 
-graphql_object!(MutationRoot: AppServerSchedulerWrapped |&self| {
-    field foo() -> String {
-        "Bar".to_owned()
+fn update_world<F>(scheduler: &AppServerSchedulerWrapped, f: F) -> FieldResult<World>
+    where F: FnOnce(&mut AppServer) -> Result<()>
+{
+    let world = {
+        let mut guard = scheduler.wrapped_server.lock().unwrap();
+        let app_server = guard.deref_mut();
+        f(app_server)?;
+        app_server.world.clone()
+    };
+    scheduler.clone().kick_scheduler();
+    Ok(world)
+}
+
+/// Validates `duration_secs` the same way as the REST `time_secs`.
+fn time_bound(duration_secs: Option<i32>, from: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    match duration_secs {
+        Some(secs) => time_after(from, i64::from(secs)).map(Some),
+        None => Ok(None),
     }
+}
+
+graphql_object!(MutationRoot: AppServerSchedulerWrapped |&self| {
+    field open_device(&executor, mac: String, duration_secs: Option<i32>) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.open_device(Some(&mac), time_bound(duration_secs, Utc::now())?)
+        })
+    },
+
+    field close_device(&executor, mac: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.close_device(Some(&mac))
+        })
+    },
+
+    field extend_device(&executor, mac: String, duration_secs: i32) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.extend_device(Some(&mac), Some(&duration_secs.to_string()))
+        })
+    },
+
+    field schedule_device(&executor,
+                          mac: String,
+                          start: DateTime<Utc>,
+                          duration_secs: Option<i32>)
+            -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            // The duration counts from the start rather than from now.
+            let time_bound = time_bound(duration_secs, start)?;
+            app_server.schedule_device(Some(&mac), Some(start), time_bound)
+        })
+    },
+
+    field cancel_pending(&executor, mac: String, start: DateTime<Utc>) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.cancel_pending(Some(&mac), Some(start))
+        })
+    },
+
+    field open_group(&executor, name: String, duration_secs: Option<i32>) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.open_group(Some(&name), time_bound(duration_secs, Utc::now())?)
+        })
+    },
+
+    field close_group(&executor, name: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.close_group(Some(&name))
+        })
+    },
+
+    field set_guest_path(&executor, open: bool, duration_secs: Option<i32>)
+            -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            let time_bound = time_bound(duration_secs, Utc::now())?;
+            app_server.set_guest_path(Some(&open.to_string()), time_bound)
+        })
+    },
+
+    field set_device_override(&executor,
+                              device_override: Option<DeviceOverride>,
+                              duration_secs: Option<i32>)
+            -> FieldResult<World> {
+        let override_str = match device_override {
+            None => "null",
+            Some(DeviceOverride::Open) => "true",
+            Some(DeviceOverride::Closed) => "false",
+        };
+        update_world(executor.context(), |app_server| {
+            let time_bound = time_bound(duration_secs, Utc::now())?;
+            app_server.set_device_override(Some(override_str), time_bound)
+        })
+    },
+
+    field add_device(&executor, mac: String, name: String, daily_quota_mins: Option<i32>)
+            -> FieldResult<World> {
+        let quota = daily_quota_mins.map(|q| q.to_string());
+        update_world(executor.context(), |app_server| {
+            app_server.add_device(Some(&mac), Some(&name), quota.as_ref().map(|q| q.as_ref()))
+        })
+    },
+
+    field adopt_device(&executor,
+                       mac: String,
+                       name: Option<String>,
+                       open: Option<bool>,
+                       group: Option<String>,
+                       duration_secs: Option<i32>)
+            -> FieldResult<World> {
+        let open = open.map(|o| o.to_string());
+        update_world(executor.context(), |app_server| {
+            app_server.adopt_device(Some(&mac),
+                                    name.as_ref().map(|n| n.as_ref()),
+                                    open.as_ref().map(|o| o.as_ref()),
+                                    group.as_ref().map(|g| g.as_ref()),
+                                    time_bound(duration_secs, Utc::now())?)
+        })
+    },
+
+    field ignore_device(&executor, mac: String, name: Option<String>) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.ignore_device(Some(&mac), name.as_ref().map(|n| n.as_ref()))
+        })
+    },
+
+    field unignore_device(&executor, mac: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.unignore_device(Some(&mac))
+        })
+    },
+
+    field remove_device(&executor, mac: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.remove_device(Some(&mac))
+        })
+    },
+
+    field rename_device(&executor, mac: String, name: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.rename_device(Some(&mac), Some(&name))
+        })
+    },
+
+    field change_device_mac(&executor, mac: String, new_mac: String) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.change_device_mac(Some(&mac), Some(&new_mac))
+        })
+    },
+
+    field refresh_devices(&executor) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.refresh_devices()
+        })
+    },
+
+    field add_weekly_rule(&executor, mac: String, days: String, start: String, end: String)
+            -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.add_weekly_rule(Some(&mac), Some(&days), Some(&start), Some(&end))
+        })
+    },
+
+    field remove_weekly_rule(&executor, id: i32) -> FieldResult<World> {
+        update_world(executor.context(), |app_server| {
+            app_server.remove_weekly_rule(Some(&id.to_string()))
+        })
+    },
 });
 
 fn iron_err(msg: &str) -> IronError {
@@ -146,3 +373,99 @@ impl <'a> Handler for GraphQLHandler<'a> where 'a: 'static {
         self.execute(&graphql_request)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use juniper::{execute, RootNode, Variables, ExecutionError};
+    use app_server::{AppServerSchedulerWrapped, new_wrapped_scheduler};
+    use app_server::test::app_server_fixture;
+    use files::test::TempDir;
+    use graphql::{QueryRoot, MutationRoot};
+    use schedule::{GuestPath, DeviceOverride};
+    use mac::test::mac;
+
+    fn context(name: &str) -> (TempDir, AppServerSchedulerWrapped) {
+        let (dir, app_server) = app_server_fixture(name, "");
+        (dir, Arc::new(new_wrapped_scheduler(Arc::new(Mutex::new(app_server)))))
+    }
+
+    fn run(context: &AppServerSchedulerWrapped, query: &str) -> Vec<ExecutionError> {
+        let schema = RootNode::new(QueryRoot, MutationRoot);
+        execute(query, None, &schema, &Variables::new(), context).unwrap().1
+    }
+
+    #[test]
+    fn open_device_duration() {
+        let (_dir, context) = context("gql-open");
+        let open_secs = |context: &AppServerSchedulerWrapped| {
+            let guard = context.wrapped_server.lock().unwrap();
+            guard.world.schedule.open_device_entries.iter()
+                .find(|e| e.item.mac == mac("00:00:00:00:ab:cd"))
+                .map(|e| e.time_bound.is_some())
+        };
+        for bad in &["0", "-60"] {
+            let query = format!("mutation {{ openDevice(mac: \"00:00:00:00:ab:cd\", \
+                                 durationSecs: {}) {{ __typename }} }}",
+                                bad);
+            assert!(!run(&context, &query).is_empty(), "{} accepted", bad);
+            assert_eq!(None, open_secs(&context));
+        }
+        assert!(run(&context,
+                    "mutation { openDevice(mac: \"00:00:00:00:ab:cd\", durationSecs: 600) \
+                     { __typename } }")
+            .is_empty());
+        assert_eq!(Some(true), open_secs(&context));
+    }
+
+    #[test]
+    fn guest_path_and_override_duration() {
+        let (_dir, context) = context("gql-guest");
+        assert!(!run(&context,
+                     "mutation { setGuestPath(open: true, durationSecs: -1) { __typename } }")
+            .is_empty());
+        assert!(!run(&context,
+                     "mutation { setDeviceOverride(deviceOverride: OPEN, durationSecs: 0) \
+                      { __typename } }")
+            .is_empty());
+        {
+            let guard = context.wrapped_server.lock().unwrap();
+            assert_eq!(GuestPath::Closed, guard.world.schedule.guest_entry.item);
+            assert_eq!(Some(DeviceOverride::Closed),
+                       guard.world.schedule.override_entry.as_ref().map(|e| e.item.clone()));
+        }
+
+        assert!(run(&context,
+                    "mutation { setGuestPath(open: true, durationSecs: 60) { __typename } }")
+            .is_empty());
+        assert!(run(&context,
+                    "mutation { setDeviceOverride(deviceOverride: OPEN, durationSecs: 60) \
+                     { __typename } }")
+            .is_empty());
+        let guard = context.wrapped_server.lock().unwrap();
+        assert_eq!(GuestPath::Open, guard.world.schedule.guest_entry.item);
+        assert!(guard.world.schedule.guest_entry.time_bound.is_some());
+        let override_entry = guard.world.schedule.override_entry.clone().unwrap();
+        assert_eq!(DeviceOverride::Open, override_entry.item);
+        assert!(override_entry.time_bound.is_some());
+    }
+
+    #[test]
+    fn add_device() {
+        let (_dir, context) = context("gql-add");
+        assert!(run(&context,
+                    "mutation { addDevice(mac: \"00:00:00:00:77:77\", name: \"Tablet\", \
+                     dailyQuotaMins: 30) { __typename } }")
+            .is_empty());
+        assert!(!run(&context,
+                     "mutation { addDevice(mac: \"nonsense\", name: \"Tablet\") \
+                      { __typename } }")
+            .is_empty());
+        let guard = context.wrapped_server.lock().unwrap();
+        let dev = guard.config.known_devices.iter()
+            .find(|d| d.mac == mac("00:00:00:00:77:77"))
+            .unwrap();
+        assert_eq!(("Tablet", Some(30)), (dev.name.as_ref(), dev.daily_quota_mins));
+        assert!(guard.world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:77:77")));
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use chrono::{DateTime, Utc};
+use serde_json;
+
+use schedule::{World, ScheduleEntry, Device, MacAddress};
+use errors::{Result, ResultExt};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, GraphQLEnum)]
+pub enum EventSource {
+    Api,
+    Scheduler,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, GraphQLEnum)]
+pub enum EventKind {
+    DeviceOpened,
+    DeviceClosed,
+    GuestPathChanged,
+    OverrideChanged,
+    UnknownDeviceSeen,
+}
+
+/// One line of the history file.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, GraphQLObject)]
+pub struct Event {
+    pub time: DateTime<Utc>,
+    pub source: EventSource,
+    pub kind: EventKind,
+    pub mac: Option<MacAddress>,
+    pub name: Option<String>,
+    /// New guest path or override state.
+    pub state: Option<String>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub mac: Option<MacAddress>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        self.from.map(|from| event.time >= from).unwrap_or(true) &&
+        self.to.map(|to| event.time < to).unwrap_or(true) &&
+        self.mac.as_ref().map(|mac| event.mac.as_ref() == Some(mac)).unwrap_or(true)
+    }
+}
+
+fn event(time: DateTime<Utc>, source: EventSource, kind: EventKind) -> Event {
+    Event {
+        time,
+        source,
+        kind,
+        mac: None,
+        name: None,
+        state: None,
+        until: None,
+    }
+}
+
+fn device_event(time: DateTime<Utc>,
+                source: EventSource,
+                kind: EventKind,
+                dev: &Device,
+                until: Option<DateTime<Utc>>)
+                -> Event {
+    Event {
+        mac: Some(dev.mac.clone()),
+        name: Some(dev.name.clone()),
+        until,
+        ..event(time, source, kind)
+    }
+}
+
+/// Lists the access changes between two versions of the world.
+pub fn world_events(old: &World,
+                    new: &World,
+                    time: DateTime<Utc>,
+                    source: EventSource)
+                    -> Vec<Event> {
+    let mut events = vec![];
+    let old_open: BTreeMap<&MacAddress, &ScheduleEntry<Device>> = old.schedule
+        .open_device_entries
+        .iter()
+        .map(|e| (&e.item.mac, e))
+        .collect();
+
+    for entry in &new.schedule.open_device_entries {
+        let changed = old_open.get(&entry.item.mac)
+            .map(|old_entry| old_entry.time_bound != entry.time_bound)
+            .unwrap_or(true);
+        if changed {
+            events.push(device_event(time,
+                                     source,
+                                     EventKind::DeviceOpened,
+                                     &entry.item,
+                                     entry.time_bound));
+        }
+    }
+    for dev in &new.closed_devices {
+        if old_open.contains_key(&dev.mac) {
+            events.push(device_event(time, source, EventKind::DeviceClosed, dev, None));
+        }
+    }
+
+    let guest = &new.schedule.guest_entry;
+    if *guest != old.schedule.guest_entry {
+        events.push(Event {
+            state: Some(format!("{:?}", guest.item)),
+            until: guest.time_bound,
+            ..event(time, source, EventKind::GuestPathChanged)
+        });
+    }
+    if new.schedule.override_entry != old.schedule.override_entry {
+        let entry = &new.schedule.override_entry;
+        events.push(Event {
+            state: Some(entry.as_ref()
+                .map(|e| format!("{:?}", e.item))
+                .unwrap_or_else(|| "None".to_owned())),
+            until: entry.as_ref().and_then(|e| e.time_bound),
+            ..event(time, source, EventKind::OverrideChanged)
+        });
+    }
+
+    let old_unknown: BTreeSet<&MacAddress> = old.unknown_devices.iter().map(|d| &d.mac).collect();
+    for dev in &new.unknown_devices {
+        if !old_unknown.contains(&dev.mac) {
+            events.push(device_event(time, source, EventKind::UnknownDeviceSeen, dev, None));
+        }
+    }
+    events
+}
+
+fn rotated_name(file_name: &str) -> String {
+    format!("{}.1", file_name)
+}
+
+/// Appends `events` as JSON lines, first moving the file aside to
+/// `file_name.1` once it has grown past `max_bytes`.
+pub fn append_events(file_name: &str, max_bytes: u64, events: &[Event]) -> Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    if fs::metadata(file_name).map(|m| m.len() >= max_bytes).unwrap_or(false) {
+        fs::rename(file_name, rotated_name(file_name))
+            .chain_err(|| format!("Failed to rotate {}", file_name))?;
+    }
+    let mut lines = String::new();
+    for event in events {
+        lines.push_str(&serde_json::to_string(event).chain_err(|| "Failed to serialize event")?);
+        lines.push('\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file_name)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .chain_err(|| format!("Failed to append to {}", file_name))
+}
+
+fn read_file_events(file_name: &str, filter: &EventFilter, events: &mut Vec<Event>) -> Result<()> {
+    if !Path::new(file_name).exists() {
+        return Ok(());
+    }
+    let reader = File::open(file_name).chain_err(|| format!("Failed to open {}", file_name))?;
+    for line_result in BufReader::new(reader).lines() {
+        let line = line_result.chain_err(|| "Failed to read line")?;
+        // A torn last line after a crash is skipped rather than failing the query.
+        if let Ok(event) = serde_json::from_str::<Event>(&line) {
+            if filter.matches(&event) {
+                events.push(event);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads the events matching `filter`, oldest first.
+pub fn read_events(file_name: &str, filter: &EventFilter) -> Result<Vec<Event>> {
+    let mut events = vec![];
+    read_file_events(&rotated_name(file_name), filter, &mut events)?;
+    read_file_events(file_name, filter, &mut events)?;
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use chrono::{Utc, TimeZone};
+    use files::test::TempDir;
+    use schedule::test::world_fixture;
+    use schedule::{Device, GuestPath, MacAddress};
+    use mac::test::mac;
+    use history::{world_events, append_events, read_events, EventFilter, EventKind,
+                  EventSource};
+
+    #[test]
+    fn events_from_changes() {
+        let old = world_fixture();
+        let mut new = old.clone();
+        let time = Utc.ymd(2017, 2, 1).and_hms(10, 0, 0);
+        let until = Utc.ymd(2017, 2, 1).and_hms(11, 0, 0);
+        new.open_device(&mac("00:00:00:00:bb:bb"), Some(until)).unwrap();
+        new.close_device(&mac("00:00:00:00:56:78")).unwrap();
+        new.schedule.guest_entry.item = GuestPath::Open;
+        new.unknown_devices.insert(Device {
+            name: "Phone".to_owned(),
+            mac: mac("00:00:00:00:99:99"),
+            daily_quota_mins: None,
+        });
+
+        let events = world_events(&old, &new, time, EventSource::Api);
+        let kinds: Vec<(EventKind, Option<MacAddress>)> =
+            events.iter().map(|e| (e.kind, e.mac.clone())).collect();
+        assert_eq!(vec![(EventKind::DeviceOpened, Some(mac("00:00:00:00:bb:bb"))),
+                        (EventKind::DeviceClosed, Some(mac("00:00:00:00:56:78"))),
+                        (EventKind::GuestPathChanged, None),
+                        (EventKind::UnknownDeviceSeen, Some(mac("00:00:00:00:99:99")))],
+                   kinds);
+        assert_eq!(Some(until), events[0].until);
+        assert_eq!(Some("Open".to_owned()), events[2].state);
+        assert!(world_events(&new, &new, time, EventSource::Api).is_empty());
+    }
+
+    #[test]
+    fn append_rotate_and_filter() {
+        let dir = TempDir::new("history");
+        let file_name = dir.path("history.jsonl");
+
+        let old = world_fixture();
+        let mut new = old.clone();
+        new.close_device(&mac("00:00:00:00:56:78")).unwrap();
+        new.close_device(&mac("00:00:00:00:12:34")).unwrap();
+        for hour in 1..5 {
+            let time = Utc.ymd(2017, 2, 1).and_hms(hour, 0, 0);
+            append_events(&file_name, 100, &world_events(&old, &new, time, EventSource::Api))
+                .unwrap();
+        }
+        assert!(fs::metadata(format!("{}.1", file_name)).is_ok());
+
+        let all = read_events(&file_name, &EventFilter::default()).unwrap();
+        assert_eq!(4, all.len());
+        assert_eq!(Utc.ymd(2017, 2, 1).and_hms(3, 0, 0), all[0].time);
+        let filter = EventFilter {
+            from: Some(Utc.ymd(2017, 2, 1).and_hms(4, 0, 0)),
+            to: None,
+            mac: Some(mac("00:00:00:00:12:34")),
+        };
+        let filtered = read_events(&file_name, &filter).unwrap();
+        assert_eq!(1, filtered.len());
+        assert_eq!(Some(mac("00:00:00:00:12:34")), filtered[0].mac);
+    }
+}
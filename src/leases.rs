@@ -0,0 +1,315 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use chrono::{DateTime, Utc, TimeZone, NaiveDateTime};
+
+pub use ::types::{LeaseFormat, MacAddress};
+use errors::{Result, ResultExt};
+
+/// A device found in a DHCP server's lease database.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Lease {
+    pub mac: MacAddress,
+    pub hostname: Option<String>,
+    pub ip: IpAddr,
+    /// `None` for leases which never expire.
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+graphql_object!(Lease: () |&self| {
+    field mac() -> &MacAddress {&self.mac},
+    field hostname() -> &Option<String> {&self.hostname},
+    field ip() -> String {self.ip.to_string()},
+    field expiry() -> Option<DateTime<Utc>> {self.expiry},
+});
+
+fn malformed(line_no: usize, line: &str) -> String {
+    format!("Malformed lease on line {}: {}", line_no + 1, line)
+}
+
+fn parse_ip(s: &str, line_no: usize, line: &str) -> Result<IpAddr> {
+    s.parse::<IpAddr>().chain_err(|| malformed(line_no, line))
+}
+
+fn epoch_expiry(s: &str, line_no: usize, line: &str) -> Result<Option<DateTime<Utc>>> {
+    let secs = s.parse::<i64>().chain_err(|| malformed(line_no, line))?;
+    Ok(if secs > 0 {
+        Some(Utc.timestamp(secs, 0))
+    } else {
+        None
+    })
+}
+
+fn hostname(s: &str) -> Option<String> {
+    match s {
+        "" | "*" | "-" => None,
+        name => Some(name.to_owned()),
+    }
+}
+
+/// dnsmasq: `<expiry> <mac> <ip> <hostname|*> <client-id|*>`. IPv6 leases
+/// follow a `duid` line and carry an IAID instead of a MAC, so they are
+/// skipped, as are leases for non-Ethernet hardware.
+fn parse_dnsmasq(contents: &str) -> Result<Vec<Lease>> {
+    let mut leases = vec![];
+    let mut ipv6 = false;
+    for (line_no, line) in contents.lines().enumerate() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            continue;
+        }
+        if parts[0] == "duid" {
+            ipv6 = true;
+            continue;
+        }
+        if parts.len() < 4 {
+            return Err(malformed(line_no, line).into());
+        }
+        let expiry = epoch_expiry(parts[0], line_no, line)?;
+        let ip = parse_ip(parts[2], line_no, line)?;
+        if ipv6 {
+            continue;
+        }
+        let mac = match parts[1].parse::<MacAddress>() {
+            Ok(mac) => mac,
+            Err(_) => continue,
+        };
+        leases.push(Lease {
+            mac,
+            hostname: hostname(parts[3]),
+            ip,
+            expiry,
+        });
+    }
+    Ok(leases)
+}
+
+/// odhcpd state file lines look like
+/// `# <iface> <duid|mac> <iaid|ipv4> <hostname> <valid until> <id> <len> <addr>/<len> ...`.
+/// Only the DHCPv4 entries have a MAC.
+fn parse_odhcpd(contents: &str) -> Result<Vec<Lease>> {
+    let mut leases = vec![];
+    for (line_no, line) in contents.lines().enumerate() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.first() != Some(&"#") {
+            // Blank lines and plain hosts file entries.
+            continue;
+        }
+        if parts.len() < 8 {
+            return Err(malformed(line_no, line).into());
+        }
+        let expiry = epoch_expiry(parts[5], line_no, line)?;
+        if parts[3] != "ipv4" || parts.len() < 9 {
+            continue;
+        }
+        let mac = parts[2].parse::<MacAddress>().chain_err(|| malformed(line_no, line))?;
+        let ip = parse_ip(parts[8].split('/').next().unwrap_or(""), line_no, line)?;
+        leases.push(Lease {
+            mac,
+            hostname: hostname(parts[4]),
+            ip,
+            expiry,
+        });
+    }
+    Ok(leases)
+}
+
+struct IscBlock {
+    ip: IpAddr,
+    mac: Option<MacAddress>,
+    hostname: Option<String>,
+    expiry: Option<DateTime<Utc>>,
+    free: bool,
+}
+
+/// ISC dhcpd `lease <ip> { ... }` blocks. The file is a log, so a later block
+/// for the same address replaces an earlier one.
+fn parse_isc(contents: &str) -> Result<Vec<Lease>> {
+    let mut by_ip: BTreeMap<IpAddr, Option<Lease>> = BTreeMap::new();
+    let mut current: Option<IscBlock> = None;
+    for (line_no, raw_line) in contents.lines().enumerate() {
+        let line = raw_line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line.trim_right_matches(';').split_whitespace().collect();
+        // A stray `;` is an empty statement.
+        if parts.is_empty() {
+            continue;
+        }
+        let mut block = match current.take() {
+            Some(block) => block,
+            None => {
+                if parts[0] == "lease" {
+                    if parts.len() != 3 || parts[2] != "{" {
+                        return Err(malformed(line_no, raw_line).into());
+                    }
+                    current = Some(IscBlock {
+                        ip: parse_ip(parts[1], line_no, raw_line)?,
+                        mac: None,
+                        hostname: None,
+                        expiry: None,
+                        free: false,
+                    });
+                }
+                continue;
+            }
+        };
+        if parts == ["}"] {
+            let lease = match block.mac {
+                Some(ref mac) if !block.free => {
+                    Some(Lease {
+                        mac: mac.clone(),
+                        hostname: block.hostname.clone(),
+                        ip: block.ip,
+                        expiry: block.expiry,
+                    })
+                }
+                _ => None,
+            };
+            by_ip.insert(block.ip, lease);
+            continue;
+        }
+        if !line.ends_with(';') {
+            return Err(malformed(line_no, raw_line).into());
+        }
+        match (parts[0], parts.len()) {
+            ("hardware", 3) => block.mac = parts[2].parse().ok(),
+            ("client-hostname", 2) => block.hostname = hostname(parts[1].trim_matches('"')),
+            ("ends", 2) if parts[1] == "never" => block.expiry = None,
+            ("ends", 4) => {
+                let naive = NaiveDateTime::parse_from_str(&parts[2..].join(" "),
+                                                          "%Y/%m/%d %H:%M:%S")
+                    .chain_err(|| malformed(line_no, raw_line))?;
+                block.expiry = Some(DateTime::from_utc(naive, Utc));
+            }
+            ("binding", 3) => block.free = parts[2] == "free",
+            _ => {}
+        }
+        current = Some(block);
+    }
+    if current.is_some() {
+        return Err("Unterminated lease block at end of file".into());
+    }
+    Ok(by_ip.into_iter().filter_map(|(_, lease)| lease).collect())
+}
+
+pub fn parse_leases(format: LeaseFormat, contents: &str) -> Result<Vec<Lease>> {
+    match format {
+        LeaseFormat::Dnsmasq => parse_dnsmasq(contents),
+        LeaseFormat::Odhcpd => parse_odhcpd(contents),
+        LeaseFormat::Isc => parse_isc(contents),
+    }
+}
+
+pub fn read_leases(file_name: &str, format: LeaseFormat) -> Result<Vec<Lease>> {
+    let mut contents = String::new();
+    File::open(file_name)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("Failed to read lease file {}", file_name))?;
+    parse_leases(format, &contents).chain_err(|| format!("Failed to parse {}", file_name))
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::{Utc, TimeZone};
+    use leases::{parse_leases, Lease, LeaseFormat};
+    use mac::test::mac;
+
+    #[test]
+    fn dnsmasq() {
+        let contents = "1486000000 00:11:22:33:44:55 192.168.1.10 laptop 01:00:11:22:33:44:55
+0 66:77:88:99:aa:bb 192.168.1.11 * *
+0 80:00:02:08:fe:80:00:00:00:00:00:00:00:02:c9:03 192.168.1.12 infiniband *
+
+duid 00:01:00:01:1f:7d:3c:1f:00:11:22:33:44:55
+1486000000 1234567 fd00::10 phone 00:01:00:01:aa
+";
+        let leases = parse_leases(LeaseFormat::Dnsmasq, contents).unwrap();
+        assert_eq!(vec![Lease {
+                            mac: mac("00:11:22:33:44:55"),
+                            hostname: Some("laptop".to_owned()),
+                            ip: "192.168.1.10".parse().unwrap(),
+                            expiry: Some(Utc.timestamp(1486000000, 0)),
+                        },
+                        Lease {
+                            mac: mac("66:77:88:99:aa:bb"),
+                            hostname: None,
+                            ip: "192.168.1.11".parse().unwrap(),
+                            expiry: None,
+                        }],
+                   leases);
+        assert!(parse_leases(LeaseFormat::Dnsmasq, "1486000000 00:11:22:33:44:55\n").is_err());
+        assert!(parse_leases(LeaseFormat::Dnsmasq, "soon 00:11:22:33:44:55 1.2.3.4 x *\n")
+            .is_err());
+    }
+
+    #[test]
+    fn odhcpd() {
+        let contents = "# br-lan 001122334455 ipv4 laptop 1486000000 a 32 192.168.1.10/32
+# br-lan 000100011f7d3c1f001122334455 8c9b8cd7 phone 1486000000 200 128 fd00::200/128
+192.168.1.10 laptop
+";
+        let leases = parse_leases(LeaseFormat::Odhcpd, contents).unwrap();
+        assert_eq!(vec![Lease {
+                            mac: mac("00:11:22:33:44:55"),
+                            hostname: Some("laptop".to_owned()),
+                            ip: "192.168.1.10".parse().unwrap(),
+                            expiry: Some(Utc.timestamp(1486000000, 0)),
+                        }],
+                   leases);
+        assert!(parse_leases(LeaseFormat::Odhcpd, "# br-lan 0011 ipv4\n").is_err());
+    }
+
+    #[test]
+    fn isc() {
+        let contents = "# The format of this file is documented in the dhcpd.leases(5) manual page.
+authoring-byte-order little-endian;
+
+lease 192.168.1.10 {
+  starts 4 2017/02/02 10:00:00;
+  ends 4 2017/02/02 22:00:00;
+  binding state active;
+  hardware ethernet 00:11:22:33:44:55;
+  client-hostname \"laptop\";
+}
+lease 192.168.1.11 {
+  ends never;
+  hardware ethernet 66:77:88:99:AA:BB;
+}
+lease 192.168.1.10 {
+  ends 5 2017/02/03 22:00:00;
+  hardware ethernet 00:11:22:33:44:55;
+  client-hostname \"laptop\";
+}
+lease 192.168.1.12 {
+  binding state free;
+  hardware ethernet 00:00:00:00:00:01;
+}
+";
+        let leases = parse_leases(LeaseFormat::Isc, contents).unwrap();
+        assert_eq!(vec![Lease {
+                            mac: mac("00:11:22:33:44:55"),
+                            hostname: Some("laptop".to_owned()),
+                            ip: "192.168.1.10".parse().unwrap(),
+                            expiry: Some(Utc.ymd(2017, 2, 3).and_hms(22, 0, 0)),
+                        },
+                        Lease {
+                            mac: mac("66:77:88:99:aa:bb"),
+                            hostname: None,
+                            ip: "192.168.1.11".parse().unwrap(),
+                            expiry: None,
+                        }],
+                   leases);
+        assert!(parse_leases(LeaseFormat::Isc, "lease 192.168.1.10 {\n  ends never;\n").is_err());
+        assert!(parse_leases(LeaseFormat::Isc, "lease nowhere {\n}\n").is_err());
+        let stray = ";\nlease 192.168.1.11 {\n  ;;\n  hardware ethernet 66:77:88:99:aa:bb;\n}\n";
+        assert_eq!(vec![mac("66:77:88:99:aa:bb")],
+                   parse_leases(LeaseFormat::Isc, stray)
+                       .unwrap()
+                       .into_iter()
+                       .map(|l| l.mac)
+                       .collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,114 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use juniper::{Value, InputValue};
+
+use errors::{Result, Error, ErrorKind};
+
+/// An Ethernet address, always held as lowercase colon separated hex such as
+/// `00:11:22:aa:bb:cc`. Only validated addresses make it into firewall rules.
+#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct MacAddress(String);
+
+impl MacAddress {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = Error;
+
+    /// Accepts `00:11:22:aa:bb:cc`, `00-11-22-AA-BB-CC` and `001122aabbcc`.
+    fn from_str(s: &str) -> Result<MacAddress> {
+        let invalid = || ErrorKind::RequestError(format!("Invalid MAC address: {}", s)).into();
+        let digits: String = match s.len() {
+            12 => s.to_owned(),
+            17 => {
+                let sep = s.as_bytes()[2];
+                if sep != b':' && sep != b'-' {
+                    return Err(invalid());
+                }
+                let parts: Vec<&str> = s.split(sep as char).collect();
+                if parts.len() != 6 || parts.iter().any(|p| p.len() != 2) {
+                    return Err(invalid());
+                }
+                parts.concat()
+            }
+            _ => return Err(invalid()),
+        };
+        if !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(invalid());
+        }
+        let digits = digits.to_lowercase();
+        let pairs: Vec<&str> = (0..6).map(|i| &digits[i * 2..i * 2 + 2]).collect();
+        Ok(MacAddress(pairs.join(":")))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Serialize for MacAddress {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for MacAddress {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D)
+                                         -> ::std::result::Result<MacAddress, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e: Error| D::Error::custom(e.to_string()))
+    }
+}
+
+graphql_scalar!(MacAddress {
+    description: "An Ethernet address such as 00:11:22:aa:bb:cc"
+
+    resolve(&self) -> Value {
+        Value::string(&self.0)
+    }
+
+    from_input_value(v: &InputValue) -> Option<MacAddress> {
+        v.as_string_value().and_then(|s| s.parse().ok())
+    }
+});
+
+#[cfg(test)]
+pub mod test {
+    use mac::MacAddress;
+
+    pub fn mac(s: &str) -> MacAddress {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_forms() {
+        for s in &["00:11:22:aa:bb:cc", "00-11-22-AA-BB-CC", "001122AabBcc"] {
+            assert_eq!("00:11:22:aa:bb:cc", s.parse::<MacAddress>().unwrap().as_str());
+        }
+        for s in &["",
+                   "00:11:22:aa:bb",
+                   "00:11:22:aa:bb:cg",
+                   "00:11-22:aa:bb:cc",
+                   "0:011:22:aa:bb:cc",
+                   "00:11:22:aa:bb:cc;",
+                   "00:11:22:aa:bb:c;",
+                   "aa; rm -rf /"] {
+            assert!(s.parse::<MacAddress>().is_err(), "{} parsed", s);
+        }
+    }
+
+    #[test]
+    fn serde_validates() {
+        let parsed: MacAddress = ::serde_json::from_str("\"00-11-22-AA-BB-CC\"").unwrap();
+        assert_eq!("\"00:11:22:aa:bb:cc\"", ::serde_json::to_string(&parsed).unwrap());
+        assert!(::serde_json::from_str::<MacAddress>("\"1234\"").is_err());
+    }
+}
@@ -24,16 +24,26 @@ extern crate juniper;
 extern crate juniper_codegen;
 extern crate juniper_iron;
 extern crate urlencoded;
+extern crate pbkdf2;
+extern crate rand;
+extern crate hyper;
 
 mod script;
+mod mac;
 mod types;
 mod server;
 mod schedule;
 mod script_handler;
+mod firewall;
 mod files;
 mod config;
 mod app_server;
 mod graphql;
+mod auth;
+mod history;
+mod leases;
+mod neighbors;
+mod notify;
 mod errors {
     error_chain!{
         errors {
@@ -48,12 +58,15 @@ mod errors {
 use schedule::World;
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::io::{self, BufRead};
 use server::run_server;
 use script_handler::ScriptHandler;
+use firewall::{RuleApplier, new_backend};
 use clap::{Arg, App};
-use files::read_json_file;
-use config::{Config, reconcile_config};
+use files::read_json_file_or_backup;
+use config::{Config, check_config, reconcile_config};
 use app_server::{AppServer, new_wrapped_scheduler, run_expiration};
+use auth::{Auth, hash_password};
 
 use errors::{Result, ResultExt};
 
@@ -68,13 +81,23 @@ fn run() -> Result<()> {
             .help("Config file")
             .value_name("FILE")
             .takes_value(true)
-            .required(true))
+            .required_unless("hash_password"))
         .arg(Arg::with_name("debug")
             .long("debug")
             .short("d")
             .help("Prints scripts instead of running them"))
+        .arg(Arg::with_name("hash_password")
+            .long("hash-password")
+            .help("Reads a password from stdin and prints the admin_password_hash for it"))
         .get_matches();
 
+    if matches.is_present("hash_password") {
+        let mut password = String::new();
+        io::stdin().lock().read_line(&mut password).chain_err(|| "Failed to read password")?;
+        println!("{}", hash_password(password.trim_right_matches(&['\r', '\n'][..]))?);
+        return Ok(());
+    }
+
     let script_handler = if matches.is_present("debug") {
         ScriptHandler::PrintScript
     } else {
@@ -83,22 +106,35 @@ fn run() -> Result<()> {
 
     let config_file: &str = matches.value_of("config_file")
         .ok_or("Config file argument required")?;
-    let config: Config = read_json_file(config_file).chain_err(|| "Failed to read config file")?;
+    let config: Config = read_json_file_or_backup(config_file)
+        .chain_err(|| "Failed to read config file")?;
+    check_config(&config).chain_err(|| "Bad config file")?;
 
     let mut internal = AppServer {
         config_file: config_file.to_owned(),
         config: config.clone(),
         world: World::default(),
-        handler: script_handler,
+        recorded_world: World::default(),
+        firewall: RuleApplier::new(new_backend(config.firewall, script_handler)),
     };
     let mut devs = std::collections::BTreeSet::new();
-    app_server::read_dhcp_devices(&config.dhcp_lease_file, &mut devs)
-        .chain_err(|| "Failed to read dhcp leases file")?;
+    let leases =
+        app_server::read_dhcp_devices(&config.dhcp_lease_file, config.lease_format, &mut devs)
+            .unwrap_or_else(|err| {
+                println!("Failed to read dhcp leases file: {:?}", err);
+                vec![]
+            });
     internal.read_or_create_world()?;
-    let reconcile_result = reconcile_config(&internal.config, &devs, &mut internal.world);
+    internal.world.leases = leases;
+    let neighbor_macs = neighbors::read_neighbor_macs(&config);
+    let reconcile_result =
+        reconcile_config(&internal.config, &devs, &neighbor_macs, &mut internal.world);
     if reconcile_result.updated_world {
         internal.write_world()?;
     }
+    // A failure is retried by the scheduler.
+    internal.apply_rules()
+        .unwrap_or_else(|err| println!("Failed to apply firewall rules: {:?}", err));
 
     let sync_app_server = Arc::new(Mutex::new(internal));
 
@@ -108,7 +144,12 @@ fn run() -> Result<()> {
         run_expiration(&mut app_server_scheduler2);
     });
 
-    run_server(&app_server_scheduler);
+    if config.admin_password_hash.is_none() {
+        println!("No admin_password_hash configured, the API is open to everyone.");
+    }
+    let auth = Arc::new(Auth::new(config.admin_password_hash.clone()));
+
+    run_server(&app_server_scheduler, auth);
 
     Ok(())
 }
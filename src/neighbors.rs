@@ -0,0 +1,180 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::Read;
+use std::net::IpAddr;
+use std::process::Command;
+
+use config::Config;
+use schedule::MacAddress;
+use errors::{Result, ResultExt};
+
+/// A host the kernel has recently talked to on a local interface.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Neighbor {
+    pub ip: IpAddr,
+    pub mac: MacAddress,
+    pub interface: String,
+}
+
+fn malformed(line_no: usize, line: &str) -> String {
+    format!("Malformed neighbor entry on line {}: {}", line_no + 1, line)
+}
+
+fn usable_mac(mac: &str) -> Option<MacAddress> {
+    match mac.parse::<MacAddress>() {
+        Ok(ref mac) if mac.as_str() != "00:00:00:00:00:00" => Some(mac.clone()),
+        _ => None,
+    }
+}
+
+/// Parses `/proc/net/arp`, skipping incomplete entries.
+pub fn parse_proc_arp(contents: &str) -> Result<Vec<Neighbor>> {
+    let mut neighbors = vec![];
+    for (line_no, line) in contents.lines().enumerate().skip(1) {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            continue;
+        }
+        if parts.len() < 6 {
+            return Err(malformed(line_no, line).into());
+        }
+        let ip = parts[0].parse::<IpAddr>().chain_err(|| malformed(line_no, line))?;
+        // ATF_COM is set once the entry has a hardware address.
+        let flags = u32::from_str_radix(parts[2].trim_left_matches("0x"), 16)
+            .chain_err(|| malformed(line_no, line))?;
+        let mac = match usable_mac(parts[3]) {
+            Some(ref mac) if flags & 0x2 != 0 => mac.clone(),
+            _ => continue,
+        };
+        neighbors.push(Neighbor {
+            ip,
+            mac,
+            interface: parts[5].to_owned(),
+        });
+    }
+    Ok(neighbors)
+}
+
+/// Parses `ip neigh` output such as
+/// `fe80::1 dev br-lan lladdr 00:11:22:33:44:55 router STALE`.
+pub fn parse_ip_neigh(contents: &str) -> Result<Vec<Neighbor>> {
+    let mut neighbors = vec![];
+    for (line_no, line) in contents.lines().enumerate() {
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            continue;
+        }
+        let ip = parts[0].parse::<IpAddr>().chain_err(|| malformed(line_no, line))?;
+        let value_after = |key: &str| {
+            parts.iter().position(|p| *p == key).and_then(|i| parts.get(i + 1)).cloned()
+        };
+        let interface = value_after("dev").ok_or_else(|| malformed(line_no, line))?;
+        let state = parts[parts.len() - 1];
+        match value_after("lladdr").and_then(usable_mac) {
+            Some(mac) if state != "FAILED" && state != "INCOMPLETE" => {
+                neighbors.push(Neighbor {
+                    ip,
+                    mac,
+                    interface: interface.to_owned(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(neighbors)
+}
+
+fn read_file(file_name: &str) -> Result<String> {
+    let mut contents = String::new();
+    File::open(file_name)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| format!("Failed to read {}", file_name))?;
+    Ok(contents)
+}
+
+fn run_command(argv: &[String]) -> Result<String> {
+    let (program, args) = argv.split_first().ok_or("Empty neighbor command")?;
+    let output = Command::new(program)
+        .args(args)
+        .output()
+        .chain_err(|| format!("Failed to run {:?}", argv))?;
+    if !output.status.success() {
+        return Err(format!("{:?} failed: {:?}", argv, output).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Reads the configured neighbor tables, leaving out the upstream hosts seen
+/// on exit interfaces.
+pub fn read_neighbors(config: &Config) -> Result<Vec<Neighbor>> {
+    let mut neighbors = vec![];
+    if let Some(ref arp_file) = config.arp_file {
+        neighbors.extend(parse_proc_arp(&read_file(arp_file)?)?);
+    }
+    if let Some(ref command) = config.ip6_neighbor_command {
+        neighbors.extend(parse_ip_neigh(&run_command(command)?)?);
+    }
+    Ok(neighbors.into_iter()
+        .filter(|n| !config.exit_interfaces.contains(&n.interface))
+        .collect())
+}
+
+/// The MACs of the local neighbors. Failures are printed rather than
+/// returned since the lease file remains the main source of devices.
+pub fn read_neighbor_macs(config: &Config) -> BTreeSet<MacAddress> {
+    match read_neighbors(config) {
+        Ok(neighbors) => neighbors.into_iter().map(|n| n.mac).collect(),
+        Err(err) => {
+            println!("Failed to read neighbors: {:?}", err);
+            BTreeSet::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use neighbors::{parse_proc_arp, parse_ip_neigh, Neighbor};
+    use mac::test::mac;
+
+    #[test]
+    fn proc_arp() {
+        let contents = "IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.10     0x1         0x2         00:11:22:33:44:55     *        br-lan
+192.168.1.11     0x1         0x0         00:00:00:00:00:00     *        br-lan
+10.0.0.1         0x1         0x2         AA:BB:CC:DD:EE:FF     *        eth0
+";
+        assert_eq!(vec![Neighbor {
+                            ip: "192.168.1.10".parse().unwrap(),
+                            mac: mac("00:11:22:33:44:55"),
+                            interface: "br-lan".to_owned(),
+                        },
+                        Neighbor {
+                            ip: "10.0.0.1".parse().unwrap(),
+                            mac: mac("aa:bb:cc:dd:ee:ff"),
+                            interface: "eth0".to_owned(),
+                        }],
+                   parse_proc_arp(contents).unwrap());
+        assert!(parse_proc_arp("header\n192.168.1.10 0x1 0x2\n").is_err());
+    }
+
+    #[test]
+    fn ip_neigh() {
+        let contents = "fe80::1 dev br-lan lladdr 00:11:22:33:44:55 router STALE
+fd00::20 dev br-lan lladdr 66:77:88:99:aa:bb REACHABLE
+fe80::3 dev br-lan  FAILED
+fe80::4 dev br-lan lladdr 66:77:88:99:aa:cc INCOMPLETE
+";
+        assert_eq!(vec![Neighbor {
+                            ip: "fe80::1".parse().unwrap(),
+                            mac: mac("00:11:22:33:44:55"),
+                            interface: "br-lan".to_owned(),
+                        },
+                        Neighbor {
+                            ip: "fd00::20".parse().unwrap(),
+                            mac: mac("66:77:88:99:aa:bb"),
+                            interface: "br-lan".to_owned(),
+                        }],
+                   parse_ip_neigh(contents).unwrap());
+        assert!(parse_ip_neigh("not-an-ip dev br-lan\n").is_err());
+    }
+}
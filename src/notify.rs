@@ -0,0 +1,120 @@
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use hyper::Client;
+use hyper::header::ContentType;
+use serde_json;
+
+use config::Config;
+use schedule::SeenDevice;
+use errors::{Result, ResultExt};
+
+/// How long a webhook may stall connecting, reading or writing before it is
+/// given up. Resolving the host name is left to the system resolver.
+const WEBHOOK_TIMEOUT_SECS: u64 = 10;
+
+/// Connects like hyper's `HttpConnector`, but gives up on each address after
+/// `WEBHOOK_TIMEOUT_SECS`.
+fn connect_with_timeout(host: &str, port: u16, scheme: &str) -> io::Result<TcpStream> {
+    if scheme != "http" {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid scheme for Http"));
+    }
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No address for host");
+    for addr in (host, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, Duration::from_secs(WEBHOOK_TIMEOUT_SECS)) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+fn post_webhook(url: &str, device: &SeenDevice) -> Result<()> {
+    let body = serde_json::to_string(device).chain_err(|| "Failed to serialize device")?;
+    let mut client = Client::with_connector(connect_with_timeout);
+    client.set_read_timeout(Some(Duration::from_secs(WEBHOOK_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(WEBHOOK_TIMEOUT_SECS)));
+    let response = client
+        .post(url)
+        .header(ContentType::json())
+        .body(&body[..])
+        .send()
+        .chain_err(|| format!("Failed to post to {}", url))?;
+    if !response.status.is_success() {
+        return Err(format!("{} answered {}", url, response.status).into());
+    }
+    Ok(())
+}
+
+fn run_command(argv: &[String], device: &SeenDevice) -> Result<()> {
+    let (program, args) = argv.split_first().ok_or("Empty new device command")?;
+    let status = Command::new(program)
+        .args(args)
+        .env("DEVICE_MAC", device.mac.as_str())
+        .env("DEVICE_NAME", &device.name)
+        .env("DEVICE_FIRST_SEEN", device.first_seen.to_rfc3339())
+        .status()
+        .chain_err(|| format!("Failed to run {:?}", argv))?;
+    if !status.success() {
+        return Err(format!("{:?} failed: {}", argv, status).into());
+    }
+    Ok(())
+}
+
+/// Runs the configured hooks for each device on a separate thread so a slow
+/// webhook does not hold up the scheduler. Failures are printed.
+pub fn notify_new_devices(config: &Config, devices: Vec<SeenDevice>) {
+    if devices.is_empty() ||
+       (config.new_device_webhook.is_none() && config.new_device_command.is_none()) {
+        return;
+    }
+    let webhook = config.new_device_webhook.clone();
+    let command = config.new_device_command.clone();
+    thread::spawn(move || {
+        for device in &devices {
+            if let Some(ref url) = webhook {
+                post_webhook(url, device)
+                    .unwrap_or_else(|err| println!("New device webhook failed: {:?}", err));
+            }
+            if let Some(ref argv) = command {
+                run_command(argv, device)
+                    .unwrap_or_else(|err| println!("New device command failed: {:?}", err));
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use notify::{run_command, connect_with_timeout};
+    use schedule::SeenDevice;
+    use mac::test::mac;
+    use chrono::{Utc, TimeZone};
+
+    #[test]
+    fn command_gets_device() {
+        let device = SeenDevice {
+            mac: mac("00:11:22:33:44:55"),
+            name: "phone".to_owned(),
+            first_seen: Utc.ymd(2018, 3, 1).and_hms(10, 0, 0),
+        };
+        let argv = |script: &str| vec!["sh".to_owned(), "-c".to_owned(), script.to_owned()];
+        run_command(&argv("test \"$DEVICE_MAC $DEVICE_NAME\" = '00:11:22:33:44:55 phone'"),
+                    &device)
+            .unwrap();
+        assert!(run_command(&argv("test \"$DEVICE_NAME\" = other"), &device).is_err());
+        assert!(run_command(&[], &device).is_err());
+    }
+
+    #[test]
+    fn webhook_connects() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        connect_with_timeout("127.0.0.1", port, "http").unwrap();
+        assert!(connect_with_timeout("127.0.0.1", port, "https").is_err());
+    }
+}
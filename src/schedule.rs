@@ -1,8 +1,151 @@
-use std::collections::BTreeSet;
-use chrono::{DateTime, Utc};
-use errors::{Result, ErrorKind};
+use std::cmp::max;
+use std::collections::{BTreeSet, BTreeMap};
+use std::str::FromStr;
+use chrono::{DateTime, Utc, TimeZone, NaiveDate, NaiveTime, Datelike, Weekday, Duration};
+use errors::{Result, Error, ErrorKind};
 
-pub use ::types::{World, Schedule, ScheduleEntry, Device, DeviceOverride, GuestPath};
+pub use ::types::{World, Schedule, ScheduleEntry, PendingEntry, Device, DeviceOverride, GuestPath,
+                  Day, WeeklyRule, QuotaUsage, SeenDevice, UnknownPolicy, MacAddress};
+
+const ALL_DAYS: [Day; 7] = [Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri, Day::Sat, Day::Sun];
+const DAY_NAMES: [&'static str; 7] =
+    ["monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday"];
+
+impl Day {
+    fn from_weekday(weekday: Weekday) -> Day {
+        ALL_DAYS[weekday.num_days_from_monday() as usize]
+    }
+}
+
+impl FromStr for Day {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Day> {
+        let lower = s.trim().to_lowercase();
+        DAY_NAMES.iter()
+            .position(|name| lower == *name || lower == name[..3])
+            .map(|i| ALL_DAYS[i])
+            .ok_or_else(|| ErrorKind::RequestError(format!("Unknown day: {}", s)).into())
+    }
+}
+
+/// Parses a comma separated list of days or day ranges, e.g. "mon-fri,sun".
+pub fn parse_days(s: &str) -> Result<BTreeSet<Day>> {
+    let mut days = BTreeSet::new();
+    for part in s.split(',').filter(|p| !p.trim().is_empty()) {
+        let mut range = part.splitn(2, '-');
+        let first: Day = range.next().unwrap_or("").parse()?;
+        let last: Day = match range.next() {
+            Some(l) => l.parse()?,
+            None => first,
+        };
+        let first_index = first as usize;
+        let mut count = (last as usize + 7 - first_index) % 7 + 1;
+        while count > 0 {
+            count -= 1;
+            days.insert(ALL_DAYS[(first_index + count) % 7]);
+        }
+    }
+    if days.is_empty() {
+        return Err(ErrorKind::RequestError("No days given".to_owned()).into());
+    }
+    Ok(days)
+}
+
+/// Timed changes last at most a year, which also keeps the date arithmetic
+/// well clear of overflowing.
+pub const MAX_TIME_SECS: i64 = 366 * 24 * 60 * 60;
+
+/// How many devices `World::seen_devices` remembers.
+pub const MAX_SEEN_DEVICES: usize = 1000;
+
+/// A daily quota can be at most the whole day.
+pub const MAX_QUOTA_MINS: i32 = 24 * 60;
+
+pub fn parse_quota_mins(s: &str) -> Result<i32> {
+    let mins = s.trim()
+        .parse::<i32>()
+        .map_err(|_| ErrorKind::RequestError(format!("Failed to parse daily quota: {}", s)))?;
+    if mins < 1 || mins > MAX_QUOTA_MINS {
+        return Err(ErrorKind::RequestError(format!("Daily quota must be from 1 to {} minutes",
+                                                   MAX_QUOTA_MINS))
+            .into());
+    }
+    Ok(mins)
+}
+
+pub fn parse_time_secs(s: &str) -> Result<i64> {
+    s.trim()
+        .parse::<i64>()
+        .map_err(|_| ErrorKind::RequestError(format!("Failed to parse time secs: {}", s)).into())
+}
+
+/// The end of a timed change lasting `secs` from `from`.
+pub fn time_after(from: DateTime<Utc>, secs: i64) -> Result<DateTime<Utc>> {
+    if secs <= 0 || secs > MAX_TIME_SECS {
+        return Err(ErrorKind::RequestError(format!("Time secs must be from 1 to {}",
+                                                   MAX_TIME_SECS))
+            .into());
+    }
+    from.checked_add_signed(Duration::seconds(secs))
+        .ok_or_else(|| ErrorKind::RequestError("Time bound is out of range".to_owned()).into())
+}
+
+pub fn parse_time_of_day(s: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(s.trim(), "%H:%M")
+        .map_err(|_| ErrorKind::RequestError(format!("Bad time of day: {}", s)).into())
+}
+
+impl WeeklyRule {
+    fn window_on<Tz: TimeZone>(&self, date: NaiveDate, tz: &Tz)
+                               -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        if !self.days.contains(&Day::from_weekday(date.weekday())) {
+            return None;
+        }
+        let end_date = if self.end > self.start {
+            date
+        } else {
+            date.succ()
+        };
+        let start = tz.from_local_datetime(&date.and_time(self.start)).earliest();
+        let end = tz.from_local_datetime(&end_date.and_time(self.end)).earliest();
+        match (start, end) {
+            (Some(s), Some(e)) => Some((s.with_timezone(&Utc), e.with_timezone(&Utc))),
+            _ => None,
+        }
+    }
+
+    /// The window containing `now`, if any.
+    pub fn active_window<Tz: TimeZone>(&self, now: DateTime<Utc>, tz: &Tz)
+                                       -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let today = now.with_timezone(tz).naive_local().date();
+        [today.pred(), today]
+            .iter()
+            .filter_map(|d| self.window_on(*d, tz))
+            .find(|&(s, e)| s <= now && now < e)
+    }
+
+    /// The first window start strictly after `now`.
+    pub fn next_start<Tz: TimeZone>(&self, now: DateTime<Utc>, tz: &Tz) -> Option<DateTime<Utc>> {
+        let today = now.with_timezone(tz).naive_local().date();
+        (0..8)
+            .filter_map(|n| self.window_on(today + Duration::days(n), tz))
+            .map(|(s, _)| s)
+            .find(|s| *s > now)
+    }
+}
+
+/// The most recent quota reset boundary at or before `now`.
+fn quota_period_start<Tz: TimeZone>(now: DateTime<Utc>, reset: NaiveTime, tz: &Tz)
+                                    -> DateTime<Utc> {
+    let today = now.with_timezone(tz).naive_local().date();
+    [today, today.pred(), today.pred().pred()]
+        .iter()
+        .filter_map(|d| tz.from_local_datetime(&d.and_time(reset)).earliest())
+        .map(|t| t.with_timezone(&Utc))
+        .find(|t| *t <= now)
+        .unwrap_or(now)
+}
 
 impl Default for World {
     fn default() -> World {
@@ -13,21 +156,34 @@ impl Default for World {
                     time_bound: None,
                 },
                 override_entry: None,
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: BTreeSet::new(),
             },
             closed_devices: BTreeSet::new(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         }
     }
 }
 
 impl World {
-    pub fn open_device(&mut self, mac: &str, time_bound: Option<DateTime<Utc>>) -> Result<()> {
+    pub fn open_device(&mut self,
+                       mac: &MacAddress,
+                       time_bound: Option<DateTime<Utc>>) -> Result<()> {
         let result = self.closed_devices
             .iter()
-            .find(|d| d.mac == mac)
+            .find(|d| d.mac == *mac)
             .cloned();
         if let Some(dev) = result {
+            if self.quota_remaining_secs(&dev).map(|r| r <= 0).unwrap_or(false) {
+                return Err(ErrorKind::RequestError("daily quota used up".to_owned()).into());
+            }
             self.closed_devices.remove(&dev);
             let entry = ScheduleEntry {
                 item: dev.clone(),
@@ -40,11 +196,11 @@ impl World {
         }
     }
 
-    pub fn close_device(&mut self, mac: &str) -> Result<()> {
+    pub fn close_device(&mut self, mac: &MacAddress) -> Result<()> {
         let result = self.schedule
             .open_device_entries
             .iter()
-            .find(|d| d.item.mac == mac)
+            .find(|d| d.item.mac == *mac)
             .cloned();
         if let Some(entry) = result {
             self.schedule.open_device_entries.remove(&entry);
@@ -56,6 +212,209 @@ impl World {
         }
     }
 
+    /// Pushes back when an open device closes by `secs`. A device open
+    /// without a bound, or whose bound has passed, gets one `secs` from `now`.
+    pub fn extend_device(&mut self,
+                         mac: &MacAddress,
+                         secs: i64,
+                         now: DateTime<Utc>)
+                         -> Result<DateTime<Utc>> {
+        let entry = self.schedule
+            .open_device_entries
+            .iter()
+            .find(|e| e.item.mac == *mac)
+            .cloned()
+            .ok_or_else(|| ErrorKind::RequestError("device is not open".to_owned()))?;
+        let from = match entry.time_bound {
+            Some(time_bound) if time_bound > now => time_bound,
+            _ => now,
+        };
+        let time_bound = time_after(from, secs)?;
+        self.schedule.open_device_entries.remove(&entry);
+        self.schedule.open_device_entries.insert(ScheduleEntry {
+            item: entry.item,
+            time_bound: Some(time_bound),
+        });
+        Ok(time_bound)
+    }
+
+    /// Opens every closed device in `macs` and moves the ones already open to
+    /// `time_bound`, so the whole set closes together.
+    pub fn open_devices(&mut self,
+                        macs: &BTreeSet<MacAddress>,
+                        time_bound: Option<DateTime<Utc>>)
+                        -> Result<()> {
+        let open_entries: Vec<ScheduleEntry<Device>> = self.schedule
+            .open_device_entries
+            .iter()
+            .filter(|e| macs.contains(&e.item.mac))
+            .cloned()
+            .collect();
+        let closed_macs: Vec<MacAddress> = self.closed_devices
+            .iter()
+            .filter(|d| macs.contains(&d.mac))
+            .map(|d| d.mac.clone())
+            .collect();
+        if open_entries.is_empty() && closed_macs.is_empty() {
+            return Err(ErrorKind::RequestError("no known devices in group".to_owned()).into());
+        }
+        for entry in open_entries {
+            self.schedule.open_device_entries.remove(&entry);
+            self.schedule.open_device_entries.insert(ScheduleEntry {
+                item: entry.item,
+                time_bound,
+            });
+        }
+        for mac in closed_macs {
+            self.open_device(&mac, time_bound)?;
+        }
+        Ok(())
+    }
+
+    pub fn close_devices(&mut self, macs: &BTreeSet<MacAddress>) -> Result<()> {
+        let known = self.closed_devices
+            .iter()
+            .chain(self.schedule.open_device_entries.iter().map(|e| &e.item))
+            .any(|d| macs.contains(&d.mac));
+        if !known {
+            return Err(ErrorKind::RequestError("no known devices in group".to_owned()).into());
+        }
+        let open_macs: Vec<MacAddress> = self.schedule
+            .open_device_entries
+            .iter()
+            .filter(|e| macs.contains(&e.item.mac))
+            .map(|e| e.item.mac.clone())
+            .collect();
+        for mac in open_macs {
+            self.close_device(&mac)?;
+        }
+        Ok(())
+    }
+
+    /// Moves a known device, its weekly rules, pending openings and quota
+    /// usage over to `new_mac`, leaving it open or closed as it was.
+    pub fn change_mac(&mut self, mac: &MacAddress, new_mac: &MacAddress) -> Result<()> {
+        let closed = self.closed_devices.iter().find(|d| d.mac == *mac).cloned();
+        let open = self.schedule.open_device_entries.iter().find(|e| e.item.mac == *mac).cloned();
+        if let Some(dev) = closed {
+            self.closed_devices.remove(&dev);
+            self.closed_devices.insert(Device { mac: new_mac.clone(), ..dev });
+        } else if let Some(entry) = open {
+            self.schedule.open_device_entries.remove(&entry);
+            self.schedule.open_device_entries.insert(ScheduleEntry {
+                item: Device { mac: new_mac.clone(), ..entry.item },
+                time_bound: entry.time_bound,
+            });
+        } else {
+            return Err(ErrorKind::RequestError("mac not found".to_owned()).into());
+        }
+        self.schedule.pending_device_entries = self.schedule
+            .pending_device_entries
+            .clone()
+            .into_iter()
+            .map(|e| {
+                if e.item.mac != *mac {
+                    return e;
+                }
+                PendingEntry { item: Device { mac: new_mac.clone(), ..e.item }, ..e }
+            })
+            .collect();
+        for rule in self.weekly_rules.iter_mut().filter(|r| r.mac == *mac) {
+            rule.mac = new_mac.clone();
+        }
+        for usage in self.quota_usage.iter_mut().filter(|u| u.mac == *mac) {
+            usage.mac = new_mac.clone();
+        }
+        Ok(())
+    }
+
+    /// Queues a known device to open at `start`, closing again at
+    /// `time_bound` if given.
+    pub fn schedule_device(&mut self,
+                           mac: &MacAddress,
+                           start: DateTime<Utc>,
+                           time_bound: Option<DateTime<Utc>>,
+                           now: DateTime<Utc>)
+                           -> Result<()> {
+        if start <= now {
+            return Err(ErrorKind::RequestError("Start is in the past".to_owned()).into());
+        }
+        if time_bound.map(|t| t <= start).unwrap_or(false) {
+            return Err(ErrorKind::RequestError("Time bound is before start".to_owned()).into());
+        }
+        let dev = self.closed_devices
+            .iter()
+            .chain(self.schedule.open_device_entries.iter().map(|e| &e.item))
+            .find(|d| d.mac == *mac)
+            .cloned()
+            .ok_or_else(|| ErrorKind::RequestError("mac not found".to_owned()))?;
+        self.schedule.pending_device_entries.insert(PendingEntry {
+            item: dev,
+            time_bound,
+            start,
+        });
+        Ok(())
+    }
+
+    pub fn cancel_pending(&mut self, mac: &MacAddress, start: DateTime<Utc>) -> Result<()> {
+        let len = self.schedule.pending_device_entries.len();
+        self.schedule.pending_device_entries = self.schedule
+            .pending_device_entries
+            .clone()
+            .into_iter()
+            .filter(|e| !(e.item.mac == *mac && e.start == start))
+            .collect();
+        if self.schedule.pending_device_entries.len() == len {
+            return Err(ErrorKind::RequestError("pending entry not found".to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Opens devices whose pending entries have started. A device which is
+    /// already open is never given less time than it has.
+    fn start_pending(&mut self, now: DateTime<Utc>) {
+        let started: Vec<PendingEntry> = self.schedule
+            .pending_device_entries
+            .iter()
+            .filter(|e| e.start <= now)
+            .cloned()
+            .collect();
+        for entry in started {
+            self.schedule.pending_device_entries.remove(&entry);
+            if entry.time_bound.map(|t| t <= now).unwrap_or(false) {
+                continue;
+            }
+            let current = self.schedule
+                .open_device_entries
+                .iter()
+                .find(|e| e.item.mac == entry.item.mac)
+                .cloned();
+            match current {
+                Some(open) => {
+                    let time_bound = match (open.time_bound, entry.time_bound) {
+                        (Some(a), Some(b)) => Some(max(a, b)),
+                        _ => None,
+                    };
+                    if time_bound != open.time_bound {
+                        self.schedule.open_device_entries.remove(&open);
+                        self.schedule.open_device_entries.insert(ScheduleEntry {
+                            item: open.item,
+                            time_bound,
+                        });
+                    }
+                }
+                None => {
+                    // The device may have run out of quota.
+                    if let Err(err) = self.open_device(&entry.item.mac, entry.time_bound) {
+                        println!("Failed to open {} as scheduled: {:?}",
+                                 entry.item.mac.as_str(),
+                                 err);
+                    }
+                }
+            }
+        }
+    }
+
     pub fn expire_bounded(&mut self, time_bound: DateTime<Utc>) {
         let expired_open: BTreeSet<ScheduleEntry<Device>> = self.schedule
             .open_device_entries
@@ -80,6 +439,247 @@ impl World {
         if clear_override {
             self.schedule.override_entry = None;
         }
+        self.start_pending(time_bound);
+    }
+
+    pub fn add_weekly_rule(&mut self,
+                           mac: &MacAddress,
+                           days: BTreeSet<Day>,
+                           start: NaiveTime,
+                           end: NaiveTime)
+                           -> Result<u32> {
+        let known = self.closed_devices
+            .iter()
+            .chain(self.schedule.open_device_entries.iter().map(|e| &e.item))
+            .any(|d| d.mac == *mac);
+        if !known {
+            return Err(ErrorKind::RequestError("mac not found".to_owned()).into());
+        }
+        if start == end {
+            return Err(ErrorKind::RequestError("Rule start and end are equal".to_owned())
+                .into());
+        }
+        let id = self.weekly_rules.iter().map(|r| r.id + 1).max().unwrap_or(1);
+        self.weekly_rules.push(WeeklyRule {
+            id,
+            mac: mac.clone(),
+            days,
+            start,
+            end,
+            last_applied: None,
+        });
+        Ok(id)
+    }
+
+    pub fn remove_weekly_rule(&mut self, id: u32) -> Result<()> {
+        let len = self.weekly_rules.len();
+        self.weekly_rules.retain(|r| r.id != id);
+        if self.weekly_rules.len() == len {
+            return Err(ErrorKind::RequestError("rule not found".to_owned()).into());
+        }
+        Ok(())
+    }
+
+    /// Opens devices whose weekly window has started since the rule was last
+    /// applied. The open entry is bounded by the window end so the regular
+    /// expiry closes it again.
+    pub fn apply_weekly_rules<Tz: TimeZone>(&mut self, now: DateTime<Utc>, tz: &Tz) {
+        let mut to_open = vec![];
+        for rule in &mut self.weekly_rules {
+            if let Some((start, end)) = rule.active_window(now, tz) {
+                if rule.last_applied.map(|t| t < start).unwrap_or(true) {
+                    rule.last_applied = Some(start);
+                    to_open.push((rule.mac.clone(), end));
+                }
+            }
+        }
+        for (mac, end) in to_open {
+            let current = self.schedule
+                .open_device_entries
+                .iter()
+                .find(|e| e.item.mac == mac)
+                .cloned();
+            match current {
+                Some(entry) => {
+                    if entry.time_bound.map(|t| t < end).unwrap_or(false) {
+                        self.schedule.open_device_entries.remove(&entry);
+                        self.schedule.open_device_entries.insert(ScheduleEntry {
+                            item: entry.item,
+                            time_bound: Some(end),
+                        });
+                    }
+                }
+                None => {
+                    // The device may have run out of quota.
+                    if let Err(err) = self.open_device(&mac, Some(end)) {
+                        println!("Failed to open {} for its weekly rule: {:?}", mac.as_str(), err);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn get_next_rule_time<Tz: TimeZone>(&self, now: DateTime<Utc>, tz: &Tz)
+                                            -> Option<DateTime<Utc>> {
+        self.weekly_rules.iter().flat_map(|r| r.next_start(now, tz)).min()
+    }
+
+    fn quota_remaining_secs(&self, dev: &Device) -> Option<i64> {
+        dev.daily_quota_mins.map(|quota| {
+            let used = self.quota_usage
+                .iter()
+                .find(|u| u.mac == dev.mac)
+                .map(|u| u.used_secs)
+                .unwrap_or(0);
+            i64::from(quota) * 60 - used
+        })
+    }
+
+    /// Charges the time since the last check to every open device with a
+    /// daily quota and closes the ones that have run out. Usage starts over
+    /// at `reset` local time each day.
+    pub fn account_quotas<Tz: TimeZone>(&mut self,
+                                        now: DateTime<Utc>,
+                                        reset: NaiveTime,
+                                        tz: &Tz) {
+        let period_start = quota_period_start(now, reset, tz);
+        let counted_from = self.quota_checked
+            .map(|t| if t > period_start { t } else { period_start })
+            .unwrap_or(now);
+        let elapsed = if now > counted_from {
+            now.signed_duration_since(counted_from).num_seconds()
+        } else {
+            0
+        };
+        let open_macs: BTreeSet<MacAddress> = self.schedule
+            .open_device_entries
+            .iter()
+            .map(|e| e.item.mac.clone())
+            .collect();
+        let quota_devices: Vec<(MacAddress, i32)> = self.closed_devices
+            .iter()
+            .chain(self.schedule.open_device_entries.iter().map(|e| &e.item))
+            .filter_map(|d| d.daily_quota_mins.map(|q| (d.mac.clone(), q)))
+            .collect();
+
+        let old_usage = ::std::mem::replace(&mut self.quota_usage, vec![]);
+        let mut exhausted = vec![];
+        for (mac, quota) in quota_devices {
+            let mut usage = old_usage.iter()
+                .find(|u| u.mac == mac && u.period_start == period_start)
+                .cloned()
+                .unwrap_or_else(|| {
+                    QuotaUsage {
+                        mac: mac.clone(),
+                        period_start,
+                        used_secs: 0,
+                        remaining_mins: 0,
+                    }
+                });
+            if open_macs.contains(&mac) {
+                usage.used_secs += elapsed;
+            }
+            let remaining_secs = i64::from(quota) * 60 - usage.used_secs;
+            usage.remaining_mins = if remaining_secs > 0 {
+                (remaining_secs + 59) / 60
+            } else {
+                0
+            };
+            if remaining_secs <= 0 && open_macs.contains(&mac) {
+                exhausted.push(mac.clone());
+            }
+            self.quota_usage.push(usage);
+        }
+        self.quota_checked = Some(now);
+
+        for mac in exhausted {
+            let _ = self.close_device(&mac);
+        }
+    }
+
+    /// The next quota reset, or the time an open device runs out of quota.
+    pub fn get_next_quota_time<Tz: TimeZone>(&self,
+                                             now: DateTime<Utc>,
+                                             reset: NaiveTime,
+                                             tz: &Tz)
+                                             -> Option<DateTime<Utc>> {
+        if self.quota_usage.is_empty() {
+            return None;
+        }
+        let checked = self.quota_checked.unwrap_or(now);
+        let today = now.with_timezone(tz).naive_local().date();
+        let next_reset = [today, today.succ(), today.succ().succ()]
+            .iter()
+            .filter_map(|d| tz.from_local_datetime(&d.and_time(reset)).earliest())
+            .map(|t| t.with_timezone(&Utc))
+            .find(|t| *t > now);
+        self.schedule
+            .open_device_entries
+            .iter()
+            .filter_map(|e| self.quota_remaining_secs(&e.item))
+            .map(|r| checked + Duration::seconds(if r > 0 { r } else { 0 }))
+            .chain(next_reset)
+            .min()
+    }
+
+    /// Records the unknown devices which have never been seen before and
+    /// returns them. Past `MAX_SEEN_DEVICES` the devices seen longest ago
+    /// which are no longer on the network are forgotten.
+    pub fn record_seen_devices(&mut self, now: DateTime<Utc>) -> Vec<SeenDevice> {
+        let new_devices: Vec<SeenDevice> = self.unknown_devices
+            .iter()
+            .filter(|d| {
+                !self.seen_devices.iter().any(|s| s.mac == d.mac)
+            })
+            .map(|d| {
+                SeenDevice {
+                    mac: d.mac.clone(),
+                    name: d.name.clone(),
+                    first_seen: now,
+                }
+            })
+            .collect();
+        self.seen_devices.extend(new_devices.iter().cloned());
+        if self.seen_devices.len() > MAX_SEEN_DEVICES {
+            let unknown_macs: BTreeSet<&MacAddress> =
+                self.unknown_devices.iter().map(|d| &d.mac).collect();
+            let mut excess = self.seen_devices.len() - MAX_SEEN_DEVICES;
+            // Entries are in the order they were first seen.
+            self.seen_devices.retain(|s| {
+                if excess > 0 && !unknown_macs.contains(&s.mac) {
+                    excess -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        new_devices
+    }
+
+    /// When a quarantined device gets blocked, or None if it has not been
+    /// recorded as seen yet.
+    pub fn quarantine_end(&self, mac: &MacAddress, mins: u32) -> Option<DateTime<Utc>> {
+        self.seen_devices
+            .iter()
+            .find(|s| s.mac == *mac)
+            .map(|s| s.first_seen + Duration::minutes(mins as i64))
+    }
+
+    pub fn get_next_quarantine_time(&self,
+                                    policy: UnknownPolicy,
+                                    now: DateTime<Utc>)
+                                    -> Option<DateTime<Utc>> {
+        match policy {
+            UnknownPolicy::Quarantine(mins) => {
+                self.unknown_devices
+                    .iter()
+                    .filter_map(|d| self.quarantine_end(&d.mac, mins))
+                    .filter(|t| *t > now)
+                    .min()
+            }
+            _ => None,
+        }
     }
 
     pub fn get_soonest_event_time(&self) -> Option<DateTime<Utc>> {
@@ -89,6 +689,7 @@ impl World {
             all_dates.extend(se.time_bound);
         }
         all_dates.extend(self.schedule.open_device_entries.clone().into_iter().flat_map(|se| se.time_bound));
+        all_dates.extend(self.schedule.pending_device_entries.iter().map(|se| se.start));
 
         all_dates.into_iter().min()
     }
@@ -96,9 +697,12 @@ impl World {
 
 #[cfg(test)]
 pub mod test {
-    use std::collections::BTreeSet;
-    use schedule::{Schedule, World, ScheduleEntry, GuestPath, Device, DeviceOverride};
-    use chrono::{Utc, TimeZone};
+    use std::collections::{BTreeSet, BTreeMap};
+    use schedule::{Schedule, World, ScheduleEntry, GuestPath, Device, DeviceOverride, Day,
+                   MacAddress, parse_days, parse_time_of_day, parse_time_secs, time_after,
+                   parse_quota_mins, SeenDevice, MAX_TIME_SECS, MAX_SEEN_DEVICES, MAX_QUOTA_MINS};
+    use mac::test::mac;
+    use chrono::{Utc, TimeZone, NaiveTime, Duration, MAX_DATE};
 
     pub fn world_fixture() -> World {
         return World {
@@ -111,17 +715,20 @@ pub mod test {
                     item: DeviceOverride::Closed,
                     time_bound: None,
                 }),
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: [ScheduleEntry {
                                           item: Device {
                                               name: "TV2".to_owned(),
-                                              mac: "1234".to_owned(),
+                                              mac: mac("00:00:00:00:12:34"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       },
                                       ScheduleEntry {
                                           item: Device {
                                               name: "TV1".to_owned(),
-                                              mac: "5678".to_owned(),
+                                              mac: mac("00:00:00:00:56:78"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       }]
@@ -131,23 +738,32 @@ pub mod test {
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV3".to_owned(),
-                                 mac: "bbbb".to_owned(),
+                                 mac: mac("00:00:00:00:bb:bb"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
     }
 
     #[test]
     fn open_device() {
         let mut world = world_fixture();
-        world.open_device("bbbb", None).unwrap();
+        world.open_device(&mac("00:00:00:00:bb:bb"), None).unwrap();
         let expected = World {
             schedule: Schedule {
                 guest_entry: ScheduleEntry {
@@ -158,24 +774,28 @@ pub mod test {
                     item: DeviceOverride::Closed,
                     time_bound: None,
                 }),
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: [ScheduleEntry {
                                           item: Device {
                                               name: "TV2".to_owned(),
-                                              mac: "1234".to_owned(),
+                                              mac: mac("00:00:00:00:12:34"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       },
                                       ScheduleEntry {
                                           item: Device {
                                               name: "TV1".to_owned(),
-                                              mac: "5678".to_owned(),
+                                              mac: mac("00:00:00:00:56:78"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       },
                                       ScheduleEntry {
                                           item: Device {
                                               name: "TV3".to_owned(),
-                                              mac: "bbbb".to_owned(),
+                                              mac: mac("00:00:00:00:bb:bb"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       }]
@@ -185,12 +805,20 @@ pub mod test {
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
         assert_eq!(expected, world);
     }
@@ -198,7 +826,7 @@ pub mod test {
     #[test]
     fn close_device() {
         let mut world = world_fixture();
-        world.close_device("5678").unwrap();
+        world.close_device(&mac("00:00:00:00:56:78")).unwrap();
         let expected = World {
             schedule: Schedule {
                 guest_entry: ScheduleEntry {
@@ -209,10 +837,12 @@ pub mod test {
                     item: DeviceOverride::Closed,
                     time_bound: None,
                 }),
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: [ScheduleEntry {
                                           item: Device {
                                               name: "TV2".to_owned(),
-                                              mac: "1234".to_owned(),
+                                              mac: mac("00:00:00:00:12:34"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: None,
                                       }]
@@ -222,24 +852,130 @@ pub mod test {
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV3".to_owned(),
-                                 mac: "bbbb".to_owned(),
+                                 mac: mac("00:00:00:00:bb:bb"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV1".to_owned(),
-                                 mac: "5678".to_owned(),
+                                 mac: mac("00:00:00:00:56:78"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
         assert_eq!(expected, world);
     }
 
+    #[test]
+    fn change_mac() {
+        let mut world = world_fixture();
+        let days = parse_days("mon").unwrap();
+        let time = |s| parse_time_of_day(s).unwrap();
+        world.add_weekly_rule(&mac("00:00:00:00:56:78"), days, time("08:00"), time("09:00"))
+            .unwrap();
+        world.change_mac(&mac("00:00:00:00:56:78"), &mac("00:00:00:00:99:99")).unwrap();
+        world.change_mac(&mac("00:00:00:00:ab:cd"), &mac("00:00:00:00:88:88")).unwrap();
+
+        let open: Vec<(&str, &MacAddress)> = world.schedule
+            .open_device_entries
+            .iter()
+            .map(|e| (e.item.name.as_ref(), &e.item.mac))
+            .collect();
+        assert_eq!(vec![("TV1", &mac("00:00:00:00:99:99")), ("TV2", &mac("00:00:00:00:12:34"))],
+                   open);
+        assert!(world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:88:88")));
+        assert_eq!(mac("00:00:00:00:99:99"), world.weekly_rules[0].mac);
+        assert!(world.change_mac(&mac("00:00:00:00:56:78"), &mac("00:00:00:00:77:77")).is_err());
+    }
+
+    #[test]
+    fn extend_device() {
+        let mut world = world_fixture();
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let minutes = Duration::minutes;
+        let bound = |world: &World, m: &str| {
+            world.schedule.open_device_entries.iter().find(|e| e.item.mac == mac(m)).unwrap()
+                .time_bound
+        };
+
+        world.extend_device(&mac("00:00:00:00:12:34"), 15 * 60, now).unwrap();
+        assert_eq!(Some(now + minutes(15)), bound(&world, "00:00:00:00:12:34"));
+        world.extend_device(&mac("00:00:00:00:12:34"), 15 * 60, now).unwrap();
+        assert_eq!(Some(now + minutes(30)), bound(&world, "00:00:00:00:12:34"));
+        assert_eq!(None, bound(&world, "00:00:00:00:56:78"));
+
+        // A bound which has passed is extended from now.
+        let later = now + minutes(45);
+        world.extend_device(&mac("00:00:00:00:12:34"), 15 * 60, later).unwrap();
+        assert_eq!(Some(later + minutes(15)), bound(&world, "00:00:00:00:12:34"));
+
+        assert!(world.extend_device(&mac("00:00:00:00:ab:cd"), 15 * 60, now).is_err());
+        assert!(world.extend_device(&mac("00:00:00:00:56:78"), 0, now).is_err());
+        assert!(world.extend_device(&mac("00:00:00:00:56:78"), i64::max_value(), now).is_err());
+
+        // Extending over and over runs out of dates rather than overflowing.
+        let tv2: BTreeSet<MacAddress> = [mac("00:00:00:00:12:34")].iter().cloned().collect();
+        world.open_devices(&tv2, Some(MAX_DATE.and_hms(0, 0, 0))).unwrap();
+        assert!(world.extend_device(&mac("00:00:00:00:12:34"), MAX_TIME_SECS, now).is_err());
+    }
+
+    #[test]
+    fn scheduled_opening() {
+        let mut world = world_fixture();
+        let now = Utc.ymd(2018, 3, 3).and_hms(8, 0, 0);
+        let start = Utc.ymd(2018, 3, 3).and_hms(10, 0, 0);
+        let end = Utc.ymd(2018, 3, 3).and_hms(12, 0, 0);
+        let tv4 = mac("00:00:00:00:ab:cd");
+        let is_open = |world: &World, m: &MacAddress| {
+            world.schedule.open_device_entries.iter().any(|e| e.item.mac == *m)
+        };
+
+        assert!(world.schedule_device(&tv4, now, Some(end), now).is_err());
+        assert!(world.schedule_device(&tv4, start, Some(start), now).is_err());
+        assert!(world.schedule_device(&mac("00:00:00:00:99:99"), start, None, now).is_err());
+        world.schedule_device(&tv4, start, Some(end), now).unwrap();
+        world.schedule_device(&mac("00:00:00:00:bb:bb"), start, None, now).unwrap();
+        world.cancel_pending(&mac("00:00:00:00:bb:bb"), start).unwrap();
+        assert!(world.cancel_pending(&mac("00:00:00:00:bb:bb"), start).is_err());
+        assert_eq!(Some(start), world.get_soonest_event_time());
+
+        world.expire_bounded(now);
+        assert!(!is_open(&world, &tv4));
+        world.expire_bounded(start);
+        assert!(is_open(&world, &tv4));
+        assert!(world.schedule.pending_device_entries.is_empty());
+        assert_eq!(Some(end), world.get_soonest_event_time());
+        world.expire_bounded(end);
+        assert!(!is_open(&world, &tv4));
+
+        // An opening which ran out while nobody was looking is dropped.
+        world.schedule_device(&tv4, start, Some(end), now).unwrap();
+        world.expire_bounded(end);
+        assert!(!is_open(&world, &tv4));
+        assert!(world.schedule.pending_device_entries.is_empty());
+
+        // An open device keeps the longer of its two bounds.
+        let tv2 = mac("00:00:00:00:12:34");
+        world.schedule_device(&tv2, start, Some(end), now).unwrap();
+        world.expire_bounded(start);
+        let entry = world.schedule.open_device_entries.iter().find(|e| e.item.mac == tv2);
+        assert_eq!(None, entry.unwrap().time_bound);
+    }
+
     #[test]
     fn do_timed_events() {
         let mut world = World {
@@ -252,10 +988,12 @@ pub mod test {
                     item: DeviceOverride::Open,
                     time_bound: Some(Utc.ymd(2017, 2, 1).and_hms(11, 0, 0)),
                 }),
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: [ScheduleEntry {
                                           item: Device {
                                               name: "TV2".to_owned(),
-                                              mac: "1234".to_owned(),
+                                              mac: mac("00:00:00:00:12:34"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: Some(Utc.ymd(2017, 2, 1)
                                               .and_hms(10, 0, 0)),
@@ -263,7 +1001,8 @@ pub mod test {
                                       ScheduleEntry {
                                           item: Device {
                                               name: "TV1".to_owned(),
-                                              mac: "5678".to_owned(),
+                                              mac: mac("00:00:00:00:56:78"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: Some(Utc.ymd(2017, 2, 1)
                                               .and_hms(11, 0, 0)),
@@ -274,16 +1013,25 @@ pub mod test {
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV3".to_owned(),
-                                 mac: "bbbb".to_owned(),
+                                 mac: mac("00:00:00:00:bb:bb"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
         world.expire_bounded(Utc.ymd(2017, 2, 1).and_hms(10, 30, 0));
         let expected_1 = World {
@@ -296,10 +1044,12 @@ pub mod test {
                     item: DeviceOverride::Open,
                     time_bound: Some(Utc.ymd(2017, 2, 1).and_hms(11, 0, 0)),
                 }),
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: [ScheduleEntry {
                                           item: Device {
                                               name: "TV1".to_owned(),
-                                              mac: "5678".to_owned(),
+                                              mac: mac("00:00:00:00:56:78"),
+                                              daily_quota_mins: None,
                                           },
                                           time_bound: Some(Utc.ymd(2017, 2, 1)
                                               .and_hms(11, 0, 0)),
@@ -310,20 +1060,30 @@ pub mod test {
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV2".to_owned(),
-                                 mac: "1234".to_owned(),
+                                 mac: mac("00:00:00:00:12:34"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV3".to_owned(),
-                                 mac: "bbbb".to_owned(),
+                                 mac: mac("00:00:00:00:bb:bb"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
         assert_eq!(expected_1, world);
 
@@ -335,28 +1095,40 @@ pub mod test {
                     time_bound: None,
                 },
                 override_entry: None,
+                pending_device_entries: BTreeSet::new(),
                 open_device_entries: BTreeSet::new(),
             },
             closed_devices: [Device {
                                  name: "TV4".to_owned(),
-                                 mac: "abcd".to_owned(),
+                                 mac: mac("00:00:00:00:ab:cd"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV2".to_owned(),
-                                 mac: "1234".to_owned(),
+                                 mac: mac("00:00:00:00:12:34"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV3".to_owned(),
-                                 mac: "bbbb".to_owned(),
+                                 mac: mac("00:00:00:00:bb:bb"),
+                                 daily_quota_mins: None,
                              },
                              Device {
                                  name: "TV1".to_owned(),
-                                 mac: "5678".to_owned(),
+                                 mac: mac("00:00:00:00:56:78"),
+                                 daily_quota_mins: None,
                              }]
                 .iter()
                 .cloned()
                 .collect(),
             unknown_devices: BTreeSet::new(),
+            weekly_rules: vec![],
+            quota_usage: vec![],
+            quota_checked: None,
+            groups: BTreeMap::new(),
+            ignored_devices: BTreeSet::new(),
+            seen_devices: vec![],
+            leases: vec![],
         };
         assert_eq!(expected_2, world);
     }
@@ -373,10 +1145,253 @@ pub mod test {
         assert_eq!(Some(date_2), world.get_soonest_event_time());
         let date_1 = Utc.ymd(2017, 2, 1).and_hms(0, 0, 0);
         let entry = ScheduleEntry {
-            item: Device{mac: "".to_owned(), name: "".to_owned()},
+            item: Device{mac: mac("00:00:00:00:00:01"), name: "".to_owned(), daily_quota_mins: None},
             time_bound: Some(date_1),
         };
         world.schedule.open_device_entries.insert(entry);
         assert_eq!(Some(date_1), world.get_soonest_event_time());
     }
+
+    #[test]
+    fn bounded_time_secs() {
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        assert_eq!(now + Duration::minutes(30), time_after(now, 1800).unwrap());
+        assert!(time_after(now, MAX_TIME_SECS).is_ok());
+        assert!(time_after(now, MAX_TIME_SECS + 1).is_err());
+        assert!(time_after(now, 0).is_err());
+        assert!(time_after(now, -60).is_err());
+        assert!(time_after(now, i64::max_value()).is_err());
+        assert_eq!(60, parse_time_secs(" 60").unwrap());
+        assert!(parse_time_secs("99999999999999999999").is_err());
+        assert!(parse_time_secs("soon").is_err());
+    }
+
+    #[test]
+    fn bounded_quota_mins() {
+        assert_eq!(30, parse_quota_mins("30").unwrap());
+        assert_eq!(MAX_QUOTA_MINS, parse_quota_mins("1440").unwrap());
+        for bad in &["0", "-5", "1441", "2147483647", "lots"] {
+            assert!(parse_quota_mins(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_days() {
+        let weekdays = parse_days("Mon-Fri").unwrap();
+        assert_eq!(vec![Day::Mon, Day::Tue, Day::Wed, Day::Thu, Day::Fri],
+                   weekdays.into_iter().collect::<Vec<Day>>());
+        let weekend = parse_days("sat,sunday").unwrap();
+        assert_eq!(vec![Day::Sat, Day::Sun], weekend.into_iter().collect::<Vec<Day>>());
+        let wrapped = parse_days("fri-mon").unwrap();
+        assert_eq!(vec![Day::Mon, Day::Fri, Day::Sat, Day::Sun],
+                   wrapped.into_iter().collect::<Vec<Day>>());
+        assert!(parse_days("").is_err());
+        assert!(parse_days("funday").is_err());
+        assert!(parse_days("monkey").is_err());
+        assert!(parse_days("mon-fri-sat").is_err());
+        assert!(parse_days("tues").is_err());
+    }
+
+    #[test]
+    fn weekly_rule_opens_device() {
+        let mut world = world_fixture();
+        // 2017-02-01 is a Wednesday.
+        world.add_weekly_rule(&mac("00:00:00:00:bb:bb"),
+                             parse_days("mon-fri").unwrap(),
+                             parse_time_of_day("16:00").unwrap(),
+                             parse_time_of_day("20:00").unwrap())
+            .unwrap();
+        assert!(world.add_weekly_rule(&mac("00:00:00:00:99:99"),
+                             parse_days("mon").unwrap(),
+                             parse_time_of_day("16:00").unwrap(),
+                             parse_time_of_day("20:00").unwrap())
+            .is_err());
+
+        let before = Utc.ymd(2017, 2, 1).and_hms(15, 0, 0);
+        assert_eq!(Some(Utc.ymd(2017, 2, 1).and_hms(16, 0, 0)),
+                   world.get_next_rule_time(before, &Utc));
+        world.apply_weekly_rules(before, &Utc);
+        assert!(world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:bb:bb")));
+
+        let during = Utc.ymd(2017, 2, 1).and_hms(16, 0, 0);
+        world.apply_weekly_rules(during, &Utc);
+        let entry = world.schedule
+            .open_device_entries
+            .iter()
+            .find(|e| e.item.mac == mac("00:00:00:00:bb:bb"))
+            .unwrap()
+            .clone();
+        assert_eq!(Some(Utc.ymd(2017, 2, 1).and_hms(20, 0, 0)), entry.time_bound);
+        assert_eq!(Some(Utc.ymd(2017, 2, 2).and_hms(16, 0, 0)),
+                   world.get_next_rule_time(during, &Utc));
+
+        // Closing by hand sticks until the next window.
+        world.close_device(&mac("00:00:00:00:bb:bb")).unwrap();
+        world.apply_weekly_rules(Utc.ymd(2017, 2, 1).and_hms(17, 0, 0), &Utc);
+        assert!(world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:bb:bb")));
+
+        // Friday's window opens, then Saturday has none.
+        world.apply_weekly_rules(Utc.ymd(2017, 2, 3).and_hms(19, 0, 0), &Utc);
+        assert!(world.schedule
+            .open_device_entries
+            .iter()
+            .any(|e| e.item.mac == mac("00:00:00:00:bb:bb")));
+        world.expire_bounded(Utc.ymd(2017, 2, 3).and_hms(20, 0, 0));
+        assert!(world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:bb:bb")));
+        assert_eq!(Some(Utc.ymd(2017, 2, 6).and_hms(16, 0, 0)),
+                   world.get_next_rule_time(Utc.ymd(2017, 2, 3).and_hms(20, 0, 0), &Utc));
+    }
+
+    #[test]
+    fn weekly_rule_past_midnight() {
+        let mut world = world_fixture();
+        let id = world.add_weekly_rule(&mac("00:00:00:00:ab:cd"),
+                             parse_days("sat").unwrap(),
+                             parse_time_of_day("22:00").unwrap(),
+                             parse_time_of_day("01:00").unwrap())
+            .unwrap();
+        // 2017-02-05 is a Sunday.
+        world.apply_weekly_rules(Utc.ymd(2017, 2, 5).and_hms(0, 30, 0), &Utc);
+        let entry = world.schedule
+            .open_device_entries
+            .iter()
+            .find(|e| e.item.mac == mac("00:00:00:00:ab:cd"))
+            .unwrap()
+            .clone();
+        assert_eq!(Some(Utc.ymd(2017, 2, 5).and_hms(1, 0, 0)), entry.time_bound);
+        world.remove_weekly_rule(id).unwrap();
+        assert!(world.remove_weekly_rule(id).is_err());
+        assert_eq!(None, world.get_next_rule_time(Utc.ymd(2017, 2, 5).and_hms(0, 30, 0), &Utc));
+    }
+
+    #[test]
+    fn daily_quota() {
+        let mut world = world_fixture();
+        let tv3 = world.closed_devices
+            .iter()
+            .find(|d| d.mac == mac("00:00:00:00:bb:bb"))
+            .unwrap()
+            .clone();
+        world.closed_devices.remove(&tv3);
+        world.closed_devices.insert(Device { daily_quota_mins: Some(30), ..tv3 });
+        let reset = NaiveTime::from_hms(4, 0, 0);
+
+        world.account_quotas(Utc.ymd(2017, 2, 1).and_hms(10, 0, 0), reset, &Utc);
+        world.open_device(&mac("00:00:00:00:bb:bb"), None).unwrap();
+        assert_eq!(Some(Utc.ymd(2017, 2, 1).and_hms(10, 30, 0)),
+                   world.get_next_quota_time(Utc.ymd(2017, 2, 1).and_hms(10, 0, 0), reset, &Utc));
+
+        world.account_quotas(Utc.ymd(2017, 2, 1).and_hms(10, 20, 0), reset, &Utc);
+        assert_eq!(10, world.quota_usage[0].remaining_mins);
+        world.close_device(&mac("00:00:00:00:bb:bb")).unwrap();
+
+        // Closed time is not charged.
+        world.account_quotas(Utc.ymd(2017, 2, 1).and_hms(12, 0, 0), reset, &Utc);
+        assert_eq!(10, world.quota_usage[0].remaining_mins);
+        assert_eq!(Some(Utc.ymd(2017, 2, 2).and_hms(4, 0, 0)),
+                   world.get_next_quota_time(Utc.ymd(2017, 2, 1).and_hms(12, 0, 0), reset, &Utc));
+
+        world.open_device(&mac("00:00:00:00:bb:bb"), None).unwrap();
+        world.account_quotas(Utc.ymd(2017, 2, 1).and_hms(12, 10, 0), reset, &Utc);
+        assert!(world.closed_devices.iter().any(|d| d.mac == mac("00:00:00:00:bb:bb")));
+        assert_eq!(0, world.quota_usage[0].remaining_mins);
+        assert!(world.open_device(&mac("00:00:00:00:bb:bb"), None).is_err());
+
+        // Only the time after the reset counts towards the new day.
+        world.account_quotas(Utc.ymd(2017, 2, 2).and_hms(5, 0, 0), reset, &Utc);
+        assert_eq!(30, world.quota_usage[0].remaining_mins);
+        world.open_device(&mac("00:00:00:00:bb:bb"), None).unwrap();
+    }
+
+    #[test]
+    fn open_and_close_group() {
+        let mut world = world_fixture();
+        let group: BTreeSet<MacAddress> =
+            [mac("00:00:00:00:12:34"), mac("00:00:00:00:bb:bb"), mac("00:00:00:00:99:99")]
+                .iter()
+                .cloned()
+                .collect();
+        let until = Utc.ymd(2017, 2, 1).and_hms(11, 0, 0);
+        world.open_devices(&group, Some(until)).unwrap();
+        let bounds: Vec<(MacAddress, Option<_>)> = world.schedule
+            .open_device_entries
+            .iter()
+            .map(|e| (e.item.mac.clone(), e.time_bound))
+            .collect();
+        assert_eq!(vec![(mac("00:00:00:00:56:78"), None),
+                        (mac("00:00:00:00:12:34"), Some(until)),
+                        (mac("00:00:00:00:bb:bb"), Some(until))],
+                   bounds);
+
+        world.close_devices(&group).unwrap();
+        assert_eq!(1, world.schedule.open_device_entries.len());
+        assert_eq!(3, world.closed_devices.len());
+
+        let strangers: BTreeSet<MacAddress> = [mac("00:00:00:00:99:99")].iter().cloned().collect();
+        assert!(world.open_devices(&strangers, None).is_err());
+        assert!(world.close_devices(&strangers).is_err());
+    }
+
+    #[test]
+    fn record_seen_devices() {
+        let mut world = world_fixture();
+        let first = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let later = Utc.ymd(2018, 3, 2).and_hms(10, 0, 0);
+        let unknown = |name: &str, mac_str: &str| {
+            Device {
+                name: name.to_owned(),
+                mac: mac(mac_str),
+                daily_quota_mins: None,
+            }
+        };
+        world.unknown_devices.insert(unknown("Phone", "00:00:00:00:22:22"));
+        world.unknown_devices.insert(unknown("*", "00:00:00:00:ee:ee"));
+
+        let seen = world.record_seen_devices(first);
+        assert_eq!(vec![mac("00:00:00:00:ee:ee"), mac("00:00:00:00:22:22")],
+                   seen.into_iter().map(|s| s.mac).collect::<Vec<MacAddress>>());
+        assert!(world.record_seen_devices(later).is_empty());
+
+        world.unknown_devices.clear();
+        assert!(world.record_seen_devices(later).is_empty());
+        world.unknown_devices.insert(unknown("Tablet", "00-00-00-00-EE-EE"));
+        world.unknown_devices.insert(unknown("*", "00:00:00:00:33:33"));
+        let seen = world.record_seen_devices(later);
+        assert_eq!(vec![mac("00:00:00:00:33:33")],
+                   seen.into_iter().map(|s| s.mac).collect::<Vec<MacAddress>>());
+        assert_eq!(3, world.seen_devices.len());
+        assert_eq!(first, world.seen_devices[1].first_seen);
+    }
+
+    #[test]
+    fn seen_devices_capped() {
+        let mut world = world_fixture();
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        let device = |i: usize| {
+            Device {
+                name: "*".to_owned(),
+                mac: mac(&format!("00:00:00:01:{:02x}:{:02x}", i / 256, i % 256)),
+                daily_quota_mins: None,
+            }
+        };
+        // The oldest device is still around, so the second oldest goes.
+        world.unknown_devices.insert(device(0));
+        for i in 1..MAX_SEEN_DEVICES {
+            world.seen_devices.push(SeenDevice {
+                mac: device(i - 1).mac,
+                name: "*".to_owned(),
+                first_seen: now,
+            });
+        }
+        world.unknown_devices.insert(device(MAX_SEEN_DEVICES));
+        world.unknown_devices.insert(device(MAX_SEEN_DEVICES + 1));
+
+        assert_eq!(2, world.record_seen_devices(now).len());
+        assert_eq!(MAX_SEEN_DEVICES, world.seen_devices.len());
+        let macs: Vec<MacAddress> = world.seen_devices.iter().map(|s| s.mac.clone()).collect();
+        assert_eq!(device(0).mac, macs[0]);
+        assert!(!macs.contains(&device(1).mac));
+        assert_eq!(device(2).mac, macs[1]);
+        assert_eq!(device(MAX_SEEN_DEVICES + 1).mac, macs[MAX_SEEN_DEVICES - 1]);
+    }
 }
@@ -1,11 +1,26 @@
 use std::collections::BTreeSet;
-use schedule::{World, GuestPath, DeviceOverride, ScheduleEntry};
+use std::fmt;
+use chrono::{DateTime, Utc};
+use script_handler::ScriptCommand;
+use errors::Result;
+use schedule::{World, Device, GuestPath, DeviceOverride, ScheduleEntry, UnknownPolicy,
+               MacAddress};
 
-enum Action {
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Action {
     Accept,
     Drop,
 }
 
+/// A single entry of the blocking chain, independent of the firewall used to
+/// enforce it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Rule {
+    AcceptInterface(String),
+    Mac(MacAddress, Action),
+    DropAll,
+}
+
 fn action_with_override(override_entry: &Option<ScheduleEntry<DeviceOverride>>,
                         device_action: Action)
                         -> Action {
@@ -20,6 +35,24 @@ fn action_with_override(override_entry: &Option<ScheduleEntry<DeviceOverride>>,
     }
 }
 
+fn unknown_action(world: &World,
+                  dev: &Device,
+                  policy: UnknownPolicy,
+                  now: DateTime<Utc>)
+                  -> Option<Action> {
+    match policy {
+        UnknownPolicy::Guest => None,
+        UnknownPolicy::Allow => Some(Action::Accept),
+        UnknownPolicy::Block => Some(Action::Drop),
+        UnknownPolicy::Quarantine(mins) => {
+            match world.quarantine_end(&dev.mac, mins) {
+                Some(end) if end <= now => Some(Action::Drop),
+                _ => Some(Action::Accept),
+            }
+        }
+    }
+}
+
 impl Action {
     fn script(self) -> &'static str {
         match self {
@@ -27,93 +60,484 @@ impl Action {
             Action::Drop => "DROP",
         }
     }
+
+    fn nft(self) -> &'static str {
+        match self {
+            Action::Accept => "accept",
+            Action::Drop => "drop",
+        }
+    }
 }
 
-pub fn write_script(world: &World, old_chain: &str, new_chain: &str, exit_interfaces: &BTreeSet<String>, dest: &mut String) {
-    dest.push_str(&format!("
-set -e
-set -x
-if iptables -L {old} >/dev/null 2>&1; then
-    iptables -D FORWARD -j {old} || true
-    iptables -F {old}
-    iptables -X {old}
-fi
-if iptables -L {new} >/dev/null 2>&1; then
-    iptables -E {new} {old}
-fi
-iptables -N {new}
-
-if ip6tables -L {old} >/dev/null 2>&1; then
-    ip6tables -D FORWARD -j {old} || true
-    ip6tables -F {old}
-    ip6tables -X {old}
-fi
-if ip6tables -L {new} >/dev/null 2>&1; then
-    ip6tables -E {new} {old}
-fi
-ip6tables -N {new}
-",
-                           new = new_chain,
-                           old = old_chain));
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rule::AcceptInterface(ref interface) => write!(f, "accept interface {}", interface),
+            Rule::Mac(ref mac, action) => write!(f, "{} {}", action.nft(), mac),
+            Rule::DropAll => write!(f, "drop all"),
+        }
+    }
+}
 
+pub fn build_rules(world: &World,
+                   exit_interfaces: &BTreeSet<String>,
+                   unknown_policy: UnknownPolicy,
+                   now: DateTime<Utc>)
+                   -> Vec<Rule> {
+    let mut rules = vec![];
     for interface in exit_interfaces {
-        let action = &format!(
-            "iptables -A {new} -i {eth} -j ACCEPT\n",
-            new = new_chain, eth = interface);
-        dest.push_str(action);
-        let action = &format!(
-            "ip6tables -A {new} -i {eth} -j ACCEPT\n",
-            new = new_chain, eth = interface);
-        dest.push_str(action);
+        rules.push(Rule::AcceptInterface(interface.clone()));
     }
 
     let sch = &world.schedule;
+    let known_macs: BTreeSet<&MacAddress> = world.closed_devices
+        .iter()
+        .chain(sch.open_device_entries.iter().map(|e| &e.item))
+        .map(|d| &d.mac)
+        .collect();
+    // Ahead of every other MAC rule so nothing can block them. A known
+    // device that is also ignored stays under control.
+    for dev in world.ignored_devices.iter().filter(|d| !known_macs.contains(&d.mac)) {
+        rules.push(Rule::Mac(dev.mac.clone(), Action::Accept));
+    }
+
     let device_override = &sch.override_entry;
     for entry in &sch.open_device_entries {
-        let action = action_with_override(device_override, Action::Accept).script();
-        dest.push_str(&format!("iptables -A {} -m mac --mac-source {} -j {}\n",
-                               new_chain,
-                               entry.item.mac,
-                               action));
-        dest.push_str(&format!("ip6tables -A {} -m mac --mac-source {} -j {}\n",
-                               new_chain,
-                               entry.item.mac,
-                               action));
+        let action = action_with_override(device_override, Action::Accept);
+        rules.push(Rule::Mac(entry.item.mac.clone(), action));
     }
 
     for dev in &world.closed_devices {
-        let action = action_with_override(device_override, Action::Drop).script();
-        dest.push_str(&format!("iptables -A {} -m mac --mac-source {} -j {}\n",
-                               new_chain,
-                               dev.mac,
-                               action));
-        dest.push_str(&format!("ip6tables -A {} -m mac --mac-source {} -j {}\n",
-                               new_chain,
-                               dev.mac,
-                               action));
-    }
-
-    if world.schedule.guest_entry.item == GuestPath::Closed {
-        dest.push_str(&format!("iptables -A {} -j DROP\n", new_chain));
-        dest.push_str(&format!("ip6tables -A {} -j DROP\n", new_chain));
-    }
-
-    dest.push_str(&format!("
-iptables -I FORWARD 1 -j {new}
-if iptables -L {old} >/dev/null 2>&1; then
-    iptables -D FORWARD -j {old}
-    iptables -F {old}
-    iptables -X {old}
-fi
-
-ip6tables -I FORWARD 1 -j {new}
-if ip6tables -L {old} >/dev/null 2>&1; then
-    ip6tables -D FORWARD -j {old}
-    ip6tables -F {old}
-    ip6tables -X {old}
-fi
+        let action = action_with_override(device_override, Action::Drop);
+        rules.push(Rule::Mac(dev.mac.clone(), action));
+    }
+
+    for dev in &world.unknown_devices {
+        if let Some(action) = unknown_action(world, dev, unknown_policy, now) {
+            rules.push(Rule::Mac(dev.mac.clone(), action));
+        }
+    }
+
+    if sch.guest_entry.item == GuestPath::Closed {
+        rules.push(Rule::DropAll);
+    }
+    rules
+}
+
+/// How a change would alter the firewall, with rules in their `Display` form.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, GraphQLObject)]
+pub struct RuleDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// The whole ruleset after the change.
+    pub rules: Vec<String>,
+}
+
+pub fn diff_rules(current: &[Rule], proposed: &[Rule]) -> RuleDiff {
+    let not_in = |rules: &[Rule], other: &[Rule]| {
+        rules.iter().filter(|r| !other.contains(r)).map(|r| r.to_string()).collect()
+    };
+    RuleDiff {
+        added: not_in(proposed, current),
+        removed: not_in(current, proposed),
+        rules: proposed.iter().map(|r| r.to_string()).collect(),
+    }
+}
+
+/// Linux interface names are at most 15 bytes. Anything outside this
+/// character set is refused rather than escaped; `+` is the iptables
+/// wildcard.
+fn check_interface(interface: &str) -> Result<&str> {
+    let allowed = |c: char| c.is_ascii() && (c.is_alphanumeric() || "._-+".contains(c));
+    if interface.is_empty() || interface.len() > 15 || !interface.chars().all(allowed) {
+        return Err(format!("Invalid exit interface name: {:?}", interface).into());
+    }
+    Ok(interface)
+}
+
+fn rule_spec(rule: &Rule) -> Result<String> {
+    Ok(match *rule {
+        Rule::AcceptInterface(ref interface) => {
+            format!("-i {} -j ACCEPT", check_interface(interface)?)
+        }
+        Rule::Mac(ref mac, action) => {
+            format!("-m mac --mac-source {} -j {}", mac, action.script())
+        }
+        Rule::DropAll => "-j DROP".to_owned(),
+    })
+}
+
+/// The filter table rules `iptables-save` or `iptables -S` lists for `chain`,
+/// without the `-A chain` prefix.
+fn saved_chain_rules<'a>(saved: &'a str, chain: &str) -> Vec<&'a str> {
+    let prefix = format!("-A {} ", chain);
+    saved.lines()
+        .filter(|l| l.starts_with(&prefix))
+        .map(|l| l[prefix.len()..].trim())
+        .collect()
+}
+
+fn saved_chain_exists(saved: &str, chain: &str) -> bool {
+    let declaration = format!(":{} ", chain);
+    saved.lines().any(|l| l.starts_with(&declaration))
+}
+
+fn forward_jumps(saved: &str, chain: &str) -> usize {
+    let jump = format!("-j {}", chain);
+    saved_chain_rules(saved, "FORWARD").iter().filter(|r| **r == jump).count()
+}
+
+/// Writes an `iptables-restore --noflush` payload which refills `chain` and
+/// makes sure FORWARD jumps to it exactly once, all in one commit. `saved` is
+/// the current `iptables-save -t filter` output. The `old_chain` left behind
+/// by earlier versions is removed.
+pub fn write_restore_payload(rules: &[Rule],
+                             chain: &str,
+                             old_chain: &str,
+                             saved: &str,
+                             dest: &mut String)
+                             -> Result<()> {
+    let mut specs = vec![];
+    for rule in rules {
+        specs.push(rule_spec(rule)?);
+    }
+
+    dest.push_str("*filter\n");
+    // Declaring an existing chain with --noflush empties it.
+    dest.push_str(&format!(":{} - [0:0]\n", chain));
+    if saved_chain_exists(saved, old_chain) {
+        dest.push_str(&format!(":{} - [0:0]\n", old_chain));
+        for _ in 0..forward_jumps(saved, old_chain) {
+            dest.push_str(&format!("-D FORWARD -j {}\n", old_chain));
+        }
+        dest.push_str(&format!("-X {}\n", old_chain));
+    }
+    for spec in &specs {
+        dest.push_str(&format!("-A {} {}\n", chain, spec));
+    }
+    match forward_jumps(saved, chain) {
+        0 => dest.push_str(&format!("-I FORWARD 1 -j {}\n", chain)),
+        n => {
+            for _ in 1..n {
+                dest.push_str(&format!("-D FORWARD -j {}\n", chain));
+            }
+        }
+    }
+    dest.push_str("COMMIT\n");
+    Ok(())
+}
+
+/// Compares `iptables-save` or `iptables -S` output with the rules which
+/// should be in place.
+pub fn check_saved_rules(rules: &[Rule], chain: &str, saved: &str) -> Result<()> {
+    let mut expected = vec![];
+    for rule in rules {
+        expected.push(rule_spec(rule)?);
+    }
+    let actual: Vec<String> = saved_chain_rules(saved, chain)
+        .iter()
+        .map(|r| r.to_lowercase())
+        .collect();
+    let expected: Vec<String> = expected.iter().map(|r| r.to_lowercase()).collect();
+    if actual != expected {
+        return Err(format!("Chain {} holds {:?} instead of {:?}", chain, actual, expected)
+            .into());
+    }
+    let jumps = forward_jumps(saved, chain);
+    if jumps != 1 {
+        return Err(format!("FORWARD jumps to {} {} times", chain, jumps).into());
+    }
+    Ok(())
+}
+
+/// The MACs `nft list table` shows in `set`, lowercased and sorted, or None
+/// when the set is missing.
+fn listed_nft_set(listed: &str, set: &str) -> Option<Vec<String>> {
+    let header = format!("set {} {{", set);
+    let body = &listed[listed.find(&header)? + header.len()..];
+    // The first brace closes either the elements or an empty set.
+    let body = &body[..body.find('}')?];
+    let elements = match body.find("elements = {") {
+        Some(start) => &body[start + "elements = {".len()..],
+        None => "",
+    };
+    let mut macs: Vec<String> = elements.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|m| !m.is_empty())
+        .map(|m| m.to_lowercase())
+        .collect();
+    macs.sort();
+    Some(macs)
+}
+
+/// Compares `nft list table` output with the MAC sets which should be in
+/// place.
+pub fn check_nft_sets(rules: &[Rule], listed: &str) -> Result<()> {
+    for &(set, wanted) in &[("accept_macs", Action::Accept), ("drop_macs", Action::Drop)] {
+        let mut expected: Vec<String> = rules.iter()
+            .filter_map(|r| match *r {
+                Rule::Mac(ref mac, action) if action == wanted => {
+                    Some(mac.as_str().to_lowercase())
+                }
+                _ => None,
+            })
+            .collect();
+        expected.sort();
+        let actual = listed_nft_set(listed, set);
+        if actual.as_ref() != Some(&expected) {
+            return Err(format!("Set {} holds {:?} instead of {:?}", set, actual, expected)
+                .into());
+        }
+    }
+    Ok(())
+}
+
+fn nft_set(name: &str, macs: &[&str], dest: &mut String) {
+    dest.push_str(&format!("    set {} {{\n        type ether_addr\n", name));
+    if !macs.is_empty() {
+        dest.push_str(&format!("        elements = {{ {} }}\n", macs.join(", ")));
+    }
+    dest.push_str("    }\n");
+}
+
+/// Writes `nft -f` input which replaces the `table` nftables table in a
+/// single transaction.
+pub fn write_nft_rules(rules: &[Rule], table: &str, dest: &mut String) -> Result<()> {
+    let mac_set = |wanted: Action| -> Vec<&str> {
+        rules.iter()
+            .filter_map(|r| match *r {
+                Rule::Mac(ref mac, action) if action == wanted => Some(mac.as_str()),
+                _ => None,
+            })
+            .collect()
+    };
+    let mut interfaces: Vec<String> = vec![];
+    for rule in rules {
+        if let Rule::AcceptInterface(ref interface) = *rule {
+            interfaces.push(format!("\"{}\"", check_interface(interface)?));
+        }
+    }
+
+    dest.push_str(&format!("table inet {table}
+delete table inet {table}
+table inet {table} {{
+",
+                           table = table));
+    nft_set("accept_macs", &mac_set(Action::Accept), dest);
+    nft_set("drop_macs", &mac_set(Action::Drop), dest);
+    dest.push_str("    chain forward {
+        type filter hook forward priority -1; policy accept;
+");
+    if !interfaces.is_empty() {
+        dest.push_str(&format!("        iifname {{ {} }} accept\n", interfaces.join(", ")));
+    }
+    dest.push_str(&format!("        ether saddr @accept_macs {}\n", Action::Accept.nft()));
+    dest.push_str(&format!("        ether saddr @drop_macs {}\n", Action::Drop.nft()));
+    if rules.contains(&Rule::DropAll) {
+        dest.push_str("        drop\n");
+    }
+    dest.push_str("    }
+}
+");
+    Ok(())
+}
+
+pub fn nft_commands(rules: &[Rule], table: &str) -> Result<Vec<ScriptCommand>> {
+    let mut payload = String::new();
+    write_nft_rules(rules, table, &mut payload)?;
+    Ok(vec![ScriptCommand::new(&["nft", "-f", "-"]).with_stdin(payload)])
+}
+
+#[cfg(test)]
+mod test {
+    use script::{Rule, Action, write_restore_payload, check_saved_rules, check_nft_sets,
+                 nft_commands, write_nft_rules, diff_rules};
+    use mac::test::mac;
+
+    const SAVED: &'static str = "# Generated by iptables-save v1.6.1
+*filter
+:INPUT ACCEPT [0:0]
+:FORWARD ACCEPT [0:0]
+:OUTPUT ACCEPT [0:0]
+:blocked_devices - [0:0]
+:old_blocked_devices - [0:0]
+-A FORWARD -j blocked_devices
+-A FORWARD -j old_blocked_devices
+-A FORWARD -i br-lan -j zone_lan_forward
+-A blocked_devices -i eth0 -j ACCEPT
+-A blocked_devices -m mac --mac-source 00:11:22:AA:BB:CC -j DROP
+-A blocked_devices -j DROP
+COMMIT
+";
+
+    fn rules() -> Vec<Rule> {
+        vec![Rule::AcceptInterface("eth0".to_owned()),
+             Rule::Mac(mac("00:11:22:aa:bb:cc"), Action::Drop),
+             Rule::DropAll]
+    }
+
+    #[test]
+    fn hostile_interfaces_rejected() {
+        let hostile = ["eth0; reboot", "eth0\"", "$(reboot)", "eth0 accept", "eth0\nCOMMIT", "",
+                       "a-very-long-name0"];
+        for interface in &hostile {
+            let rules = vec![Rule::AcceptInterface(interface.to_string())];
+            let mut payload = String::new();
+            assert!(write_restore_payload(&rules, "new", "old", "", &mut payload).is_err(),
+                    "{:?}",
+                    interface);
+            assert!(nft_commands(&rules, "device_blocker").is_err(), "{:?}", interface);
+        }
+    }
+
+    #[test]
+    fn restore_payload() {
+        let mut first = String::new();
+        write_restore_payload(&rules(), "blocked_devices", "old_blocked_devices", "", &mut first)
+            .unwrap();
+        assert_eq!("*filter
+:blocked_devices - [0:0]
+-A blocked_devices -i eth0 -j ACCEPT
+-A blocked_devices -m mac --mac-source 00:11:22:aa:bb:cc -j DROP
+-A blocked_devices -j DROP
+-I FORWARD 1 -j blocked_devices
+COMMIT
+",
+                   first);
+
+        let mut again = String::new();
+        write_restore_payload(&rules()[..1],
+                              "blocked_devices",
+                              "old_blocked_devices",
+                              SAVED,
+                              &mut again)
+            .unwrap();
+        assert_eq!("*filter
+:blocked_devices - [0:0]
+:old_blocked_devices - [0:0]
+-D FORWARD -j old_blocked_devices
+-X old_blocked_devices
+-A blocked_devices -i eth0 -j ACCEPT
+COMMIT
+",
+                   again);
+    }
+
+    #[test]
+    fn check_saved() {
+        let saved = SAVED.replace("-A FORWARD -j old_blocked_devices\n", "");
+        check_saved_rules(&rules(), "blocked_devices", &saved).unwrap();
+        assert!(check_saved_rules(&rules()[..2], "blocked_devices", &saved).is_err());
+        let jump = "-A FORWARD -j blocked_devices\n";
+        let doubled = saved.replace(jump, &format!("{}{}", jump, jump));
+        assert!(check_saved_rules(&rules(), "blocked_devices", &doubled).is_err());
+        assert!(check_saved_rules(&rules(), "blocked_devices", "").is_err());
+    }
+
+    #[test]
+    fn nft_payload() {
+        let nft = nft_commands(&rules(), "device_blocker").unwrap();
+        assert_eq!(vec!["nft", "-f", "-"], nft[0].argv);
+        let payload = nft[0].stdin.as_ref().unwrap();
+        assert!(payload.contains("iifname { \"eth0\" } accept"));
+        assert!(payload.contains("elements = { 00:11:22:aa:bb:cc }"));
+    }
+
+    #[test]
+    fn nft_empty() {
+        let mut payload = String::new();
+        write_nft_rules(&[], "device_blocker", &mut payload).unwrap();
+        assert_eq!("table inet device_blocker
+delete table inet device_blocker
+table inet device_blocker {
+    set accept_macs {
+        type ether_addr
+    }
+    set drop_macs {
+        type ether_addr
+    }
+    chain forward {
+        type filter hook forward priority -1; policy accept;
+        ether saddr @accept_macs accept
+        ether saddr @drop_macs drop
+    }
+}
 ",
-                           new = new_chain,
-                           old = old_chain));
+                   payload);
+    }
+
+    #[test]
+    fn nft_guest_closed() {
+        let rules = vec![Rule::AcceptInterface("eth0".to_owned()),
+                         Rule::AcceptInterface("wan+".to_owned()),
+                         Rule::Mac(mac("00:11:22:aa:bb:cc"), Action::Accept),
+                         Rule::Mac(mac("00:11:22:aa:bb:dd"), Action::Accept),
+                         Rule::Mac(mac("00:11:22:aa:bb:ee"), Action::Drop),
+                         Rule::DropAll];
+        let mut payload = String::new();
+        write_nft_rules(&rules, "device_blocker", &mut payload).unwrap();
+        assert_eq!("table inet device_blocker
+delete table inet device_blocker
+table inet device_blocker {
+    set accept_macs {
+        type ether_addr
+        elements = { 00:11:22:aa:bb:cc, 00:11:22:aa:bb:dd }
+    }
+    set drop_macs {
+        type ether_addr
+        elements = { 00:11:22:aa:bb:ee }
+    }
+    chain forward {
+        type filter hook forward priority -1; policy accept;
+        iifname { \"eth0\", \"wan+\" } accept
+        ether saddr @accept_macs accept
+        ether saddr @drop_macs drop
+        drop
+    }
+}
+",
+                   payload);
+    }
+
+    #[test]
+    fn rule_diff() {
+        let current = rules();
+        let mut proposed = current.clone();
+        proposed[1] = Rule::Mac(mac("00:11:22:aa:bb:cc"), Action::Accept);
+        proposed.pop();
+        let diff = diff_rules(&current, &proposed);
+        assert_eq!(vec!["accept 00:11:22:aa:bb:cc".to_owned()], diff.added);
+        assert_eq!(vec!["drop 00:11:22:aa:bb:cc".to_owned(), "drop all".to_owned()],
+                   diff.removed);
+        assert_eq!(vec!["accept interface eth0".to_owned(), "accept 00:11:22:aa:bb:cc".to_owned()],
+                   diff.rules);
+        assert!(diff_rules(&current, &current).added.is_empty());
+    }
+
+    #[test]
+    fn nft_sets_checked() {
+        let listed = "table inet device_blocker {
+	set accept_macs {
+		type ether_addr
+	}
 
+	set drop_macs {
+		type ether_addr
+		elements = { 00:00:00:00:00:01,
+			     00:11:22:AA:BB:CC }
+	}
+
+	chain forward {
+		type filter hook forward priority -1; policy accept;
+		ether saddr @drop_macs drop
+	}
+}
+";
+        let mut expected = rules();
+        expected.push(Rule::Mac(mac("00:00:00:00:00:01"), Action::Drop));
+        assert!(check_nft_sets(&expected, listed).is_ok());
+        assert!(check_nft_sets(&rules(), listed).is_err());
+        expected.push(Rule::Mac(mac("00:00:00:00:00:02"), Action::Accept));
+        assert!(check_nft_sets(&expected, listed).is_err());
+        assert!(check_nft_sets(&[], "table inet device_blocker {\n}\n").is_err());
+    }
 }
@@ -1,9 +1,34 @@
-use std::process::{Command, Output};
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
 
 use errors::{Result, ResultExt};
 
+/// A program run directly rather than through a shell, so nothing in `argv`
+/// or `stdin` is ever interpreted as shell syntax.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ScriptCommand {
+    pub argv: Vec<String>,
+    pub stdin: Option<String>,
+}
+
+impl ScriptCommand {
+    pub fn new(argv: &[&str]) -> ScriptCommand {
+        ScriptCommand {
+            argv: argv.iter().map(|a| a.to_string()).collect(),
+            stdin: None,
+        }
+    }
+
+    pub fn with_stdin(self, stdin: String) -> ScriptCommand {
+        ScriptCommand { stdin: Some(stdin), ..self }
+    }
+}
+
 pub trait HandleScript {
-    fn handle(&self, script: &str) -> Result<()>;
+    fn handle(&self, commands: &[ScriptCommand]) -> Result<()>;
+    /// Runs a command which only reports state and returns its output, or
+    /// `None` when commands are being printed rather than run.
+    fn read(&self, command: &ScriptCommand) -> Result<Option<String>>;
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -14,32 +39,93 @@ pub enum ScriptHandler {
 
 
 impl HandleScript for ScriptHandler {
-    fn handle(&self, script: &str) -> Result<()> {
+    fn handle(&self, commands: &[ScriptCommand]) -> Result<()> {
         match *self {
             ScriptHandler::PrintScript => {
-                println!("{}", script);
+                for command in commands {
+                    println!("{:?}", command.argv);
+                    if let Some(ref stdin) = command.stdin {
+                        println!("{}", stdin);
+                    }
+                }
+                Ok(())
+            }
+            ScriptHandler::RunScript => {
+                for command in commands {
+                    run_command(command)?;
+                }
                 Ok(())
             }
-            ScriptHandler::RunScript => run_script(script),
+        }
+    }
+
+    fn read(&self, command: &ScriptCommand) -> Result<Option<String>> {
+        match *self {
+            ScriptHandler::PrintScript => {
+                println!("{:?}", command.argv);
+                Ok(None)
+            }
+            ScriptHandler::RunScript => {
+                let output = run_command(command)?;
+                Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+            }
         }
     }
 }
 
-fn fail_script(script: &str, output: &Option<Output>) -> String {
-    format!("Failed to run script, {}:\noutput:\n{:#?}", script, output)
+fn fail_command(command: &ScriptCommand, output: &Option<Output>) -> String {
+    format!("Failed to run {:?}:\noutput:\n{:#?}", command.argv, output)
 }
 
-fn run_script(script: &str) -> Result<()> {
-    Command::new("sh")
-        .arg("-c")
-        .arg(script)
-        .output()
-        .chain_err(|| fail_script(script, &None))
-        .and_then(|output| {
-            if output.status.success() {
-                Ok(())
-            } else {
-                Err(fail_script(script, &Some(output)).into())
-            }
-        })
+fn run_command(command: &ScriptCommand) -> Result<Output> {
+    let (program, args) = command.argv
+        .split_first()
+        .ok_or_else(|| fail_command(command, &None))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .chain_err(|| fail_command(command, &None))?;
+    if let Some(ref stdin) = command.stdin {
+        child.stdin
+            .take()
+            .map(|mut pipe| pipe.write_all(stdin.as_bytes()))
+            .unwrap_or(Ok(()))
+            .chain_err(|| fail_command(command, &None))?;
+    }
+    let output = child.wait_with_output().chain_err(|| fail_command(command, &None))?;
+    if output.status.success() {
+        Ok(output)
+    } else {
+        Err(fail_command(command, &Some(output)).into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use files::test::TempDir;
+    use script_handler::{ScriptCommand, ScriptHandler, HandleScript};
+
+    #[test]
+    fn no_shell() {
+        let dir = TempDir::new("injected");
+        let marker = dir.path("marker");
+        let hostile = format!("x; touch {m} $(touch {m}) `touch {m}`", m = marker);
+        ScriptHandler::RunScript.handle(&[ScriptCommand::new(&["echo", &hostile])]).unwrap();
+        assert!(fs::metadata(&marker).is_err());
+
+        let grep = ScriptCommand::new(&["grep", "-q", "^payload$"]);
+        ScriptHandler::RunScript
+            .handle(&[grep.clone().with_stdin("payload\n".to_owned())])
+            .unwrap();
+        assert!(ScriptHandler::RunScript
+            .handle(&[grep.clone().with_stdin("other\n".to_owned())])
+            .is_err());
+        let echo = ScriptCommand::new(&["echo", "a", "b"]);
+        assert_eq!(Some("a b\n".to_owned()), ScriptHandler::RunScript.read(&echo).unwrap());
+        assert!(ScriptHandler::RunScript.read(&ScriptCommand::new(&["false"])).is_err());
+    }
 }
@@ -1,22 +1,26 @@
-use iron::{Iron, Handler, Request, Response, IronResult, Plugin};
-use iron::headers::{ETag, EntityTag};
+use iron::{Iron, Chain, Handler, Request, Response, IronResult, Plugin};
+use iron::headers::{ETag, EntityTag, SetCookie};
 use iron::modifiers::Header;
 use iron::mime::Mime;
 use iron::status;
 use router::Router;
-use params::{Params, Value};
+use params::{Params, Map, Value};
 use std::ops::DerefMut;
 use checksum::crc64::Crc64;
 use juniper_iron::{GraphiQLHandler};
 
 use serde_json;
 
-use chrono::{Utc, Duration};
+use chrono::{DateTime, Utc, Duration};
 
-use app_server::{AppServerSchedulerWrapped, AppServer, Scheduler};
+use app_server::{AppServerSchedulerWrapped, AppServer, Scheduler, Change, require_mac,
+                 parse_guest_path, parse_device_override};
+use history::EventFilter;
+use schedule::{MacAddress, parse_time_secs, time_after, MAX_TIME_SECS};
+use auth::{AuthWrapped, AuthMiddleware, SESSION_DAYS, request_token, session_cookie};
 use graphql::{QueryRoot, MutationRoot, GraphQLHandler};
 
-use ::errors::{ResultExt};
+use ::errors::{Result, ResultExt, ErrorKind};
 
 macro_rules! define_handler {
     ($t:ident, $f:ident) => {
@@ -45,6 +49,43 @@ macro_rules! define_handler {
     }
 }
 
+fn string_param<'a>(params: &'a Map, name: &str) -> Option<&'a str> {
+    match params.find(&[name]) {
+        Some(&Value::String(ref m)) => Some(m.as_ref()),
+        _ => None,
+    }
+}
+
+/// Reads the optional `time_secs` (relative) or `until` (RFC 3339) parameter
+/// bounding a timed change. Either way the bound is at most `MAX_TIME_SECS`
+/// after `now`.
+fn time_bound_param(params: &Map, now: DateTime<Utc>) -> Result<Option<DateTime<Utc>>> {
+    let time_bound = match (string_param(params, "time_secs"), string_param(params, "until")) {
+        (Some(_), Some(_)) => {
+            return Err(ErrorKind::RequestError("Give only one of time_secs and until".to_owned())
+                .into())
+        }
+        (Some(secs), None) => Some(time_after(now, parse_time_secs(secs)?)?),
+        (None, Some(until)) => {
+            let until = DateTime::parse_from_rfc3339(until)
+                .chain_err(|| ErrorKind::RequestError("Failed to parse until.".to_owned()))?;
+            Some(until.with_timezone(&Utc))
+        }
+        (None, None) => None,
+    };
+    if let Some(t) = time_bound {
+        if t <= now {
+            return Err(ErrorKind::RequestError("Time bound is in the past".to_owned()).into());
+        }
+        if t.signed_duration_since(now).num_seconds() > MAX_TIME_SECS {
+            return Err(ErrorKind::RequestError(format!("Time bound must be within {} secs",
+                                                       MAX_TIME_SECS))
+                .into());
+        }
+    }
+    Ok(time_bound)
+}
+
 const INDEX_HTML: &[u8] = include_bytes!("index.html");
 const BUNDLE_JS: &[u8] = include_bytes!("bundle.js");
 
@@ -53,8 +94,8 @@ fn get_world(
         scheduler: AppServerSchedulerWrapped, app_server: &AppServer,
         _req: &mut Request) -> IronResult<Response> {
     scheduler.kick_scheduler();
-    let world = &app_server.world;
-    let serialized = itry!(serde_json::to_string_pretty(world));
+    let world = app_server.current_world();
+    let serialized = itry!(serde_json::to_string_pretty(&world));
     Ok(Response::with((status::Ok, serialized)))
 }
 
@@ -70,13 +111,7 @@ fn open_device(
         Some(&Value::String(ref m)) => Some(m.as_ref()),
         _ => None,
     };
-    let optional_time_secs_string = params.find(&["time_secs"]);
-    let time_bound = itry!(
-        match optional_time_secs_string {
-            Some(&Value::String(ref tss)) => tss.parse::<i64>()
-                .map(|secs| Some(Utc::now() + Duration::seconds(secs))),
-            _ => Ok(None),
-        }.chain_err(|| "Failed to parse time secs."), status::BadRequest);
+    let time_bound = itry!(time_bound_param(params, Utc::now()), status::BadRequest);
     itry!(app_server.open_device(mac_param, time_bound));
     let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
     Ok(Response::with((status::Ok, serialized)))
@@ -98,6 +133,71 @@ fn close_device(
     Ok(Response::with((status::Ok, serialized)))
 }
 
+define_handler!(ExtendDeviceHandler, extend_device);
+fn extend_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.extend_device(string_param(params, "mac"), string_param(params, "time_secs")),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(ScheduleDeviceHandler, schedule_device);
+fn schedule_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    let start = itry!(date_param(params, "start"), status::BadRequest);
+    // time_secs counts from the start rather than from now.
+    let time_bound = match start {
+        Some(start) => itry!(time_bound_param(params, start), status::BadRequest),
+        None => None,
+    };
+    itry!(app_server.schedule_device(string_param(params, "mac"), start, time_bound),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(CancelPendingHandler, cancel_pending);
+fn cancel_pending(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    let start = itry!(date_param(params, "start"), status::BadRequest);
+    itry!(app_server.cancel_pending(string_param(params, "mac"), start), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(OpenGroupHandler, open_group);
+fn open_group(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    let time_bound = itry!(time_bound_param(params, Utc::now()), status::BadRequest);
+    itry!(app_server.open_group(string_param(params, "group"), time_bound), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(CloseGroupHandler, close_group);
+fn close_group(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.close_group(string_param(params, "group")), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
 define_handler!(SetGuestHandler, set_guest);
 fn set_guest(
         scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
@@ -109,7 +209,8 @@ fn set_guest(
         Some(&Value::String(ref m)) => Some(m.as_ref()),
         _ => None,
     };
-    itry!(app_server.set_guest_path(allow_param, None));
+    let time_bound = itry!(time_bound_param(params, Utc::now()), status::BadRequest);
+    itry!(app_server.set_guest_path(allow_param, time_bound), status::BadRequest);
     let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
     Ok(Response::with((status::Ok, serialized)))
 }
@@ -125,11 +226,118 @@ fn set_override_all(
         Some(&Value::String(ref m)) => Some(m.as_ref()),
         _ => None,
     };
-    itry!(app_server.set_device_override(override_param, None));
+    let time_bound = itry!(time_bound_param(params, Utc::now()), status::BadRequest);
+    itry!(app_server.set_device_override(override_param, time_bound), status::BadRequest);
     let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
     Ok(Response::with((status::Ok, serialized)))
 }
 
+/// Reads the change that posting the same parameters to `/api/<change>`
+/// would make.
+fn change_param(app_server: &AppServer, params: &Map, now: DateTime<Utc>) -> Result<Change> {
+    let time_bound = time_bound_param(params, now)?;
+    Ok(match string_param(params, "change") {
+        Some("device/open") => Change::OpenDevice(require_mac(string_param(params, "mac"))?,
+                                                  time_bound),
+        Some("device/close") => Change::CloseDevice(require_mac(string_param(params, "mac"))?),
+        Some("group/open") => {
+            Change::OpenGroup(app_server.group_macs(string_param(params, "group"))?, time_bound)
+        }
+        Some("group/close") => {
+            Change::CloseGroup(app_server.group_macs(string_param(params, "group"))?)
+        }
+        Some("guest") => {
+            Change::GuestPath(parse_guest_path(string_param(params, "allow"))?, time_bound)
+        }
+        Some("override_all") => {
+            let override_arg = parse_device_override(string_param(params, "override"))?;
+            Change::DeviceOverride(override_arg, time_bound)
+        }
+        Some(other) => {
+            return Err(ErrorKind::RequestError(format!("Cannot preview {}", other)).into())
+        }
+        None => return Err(ErrorKind::RequestError("Missing change parameter".to_owned()).into()),
+    })
+}
+
+define_handler!(PreviewChangeHandler, preview_change);
+fn preview_change(
+        _scheduler: AppServerSchedulerWrapped, app_server: &AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    let params = itry!(req.get_ref::<Params>());
+    let change = itry!(change_param(app_server, params, Utc::now()), status::BadRequest);
+    let diff = itry!(app_server.preview_change(change), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&diff));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(AddWeeklyRuleHandler, add_weekly_rule);
+fn add_weekly_rule(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.add_weekly_rule(
+        string_param(params, "mac"),
+        string_param(params, "days"),
+        string_param(params, "start"),
+        string_param(params, "end")), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(RemoveWeeklyRuleHandler, remove_weekly_rule);
+fn remove_weekly_rule(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.remove_weekly_rule(string_param(params, "id")), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+fn date_param(params: &Map, name: &str) -> Result<Option<DateTime<Utc>>> {
+    match string_param(params, name) {
+        Some(date) => {
+            DateTime::parse_from_rfc3339(date)
+                .map(|d| Some(d.with_timezone(&Utc)))
+                .chain_err(|| ErrorKind::RequestError(format!("Failed to parse {}.", name)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn mac_param(params: &Map, name: &str) -> Result<Option<MacAddress>> {
+    match string_param(params, name) {
+        Some(mac) => mac.parse().map(Some),
+        None => Ok(None),
+    }
+}
+
+define_handler!(GetFirewallStatusHandler, get_firewall_status);
+fn get_firewall_status(
+        _scheduler: AppServerSchedulerWrapped, app_server: &AppServer,
+        _req: &mut Request) -> IronResult<Response> {
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.firewall_status()));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(GetHistoryHandler, get_history);
+fn get_history(
+        _scheduler: AppServerSchedulerWrapped, app_server: &AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    let params = itry!(req.get_ref::<Params>());
+    let filter = EventFilter {
+        from: itry!(date_param(params, "from"), status::BadRequest),
+        to: itry!(date_param(params, "to"), status::BadRequest),
+        mac: itry!(mac_param(params, "mac"), status::BadRequest),
+    };
+    let events = itry!(app_server.history(&filter));
+    let serialized = itry!(serde_json::to_string_pretty(&events));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
 define_handler!(AddDeviceHandler, add_device);
 fn add_device(
         scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
@@ -146,7 +354,84 @@ fn add_device(
         Some(&Value::String(ref m)) => Some(m.as_ref()),
         _ => None,
     };
-    itry!(app_server.add_device(mac_param, name_param));
+    itry!(app_server.add_device(mac_param, name_param, string_param(params, "daily_quota_mins")),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(AdoptDeviceHandler, adopt_device);
+fn adopt_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    let time_bound = itry!(time_bound_param(params, Utc::now()), status::BadRequest);
+    itry!(app_server.adopt_device(string_param(params, "mac"),
+                                  string_param(params, "name"),
+                                  string_param(params, "open"),
+                                  string_param(params, "group"),
+                                  time_bound),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(IgnoreDeviceHandler, ignore_device);
+fn ignore_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.ignore_device(string_param(params, "mac"), string_param(params, "name")),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(UnignoreDeviceHandler, unignore_device);
+fn unignore_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.unignore_device(string_param(params, "mac")), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(RemoveDeviceHandler, remove_device);
+fn remove_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.remove_device(string_param(params, "mac")), status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(RenameDeviceHandler, rename_device);
+fn rename_device(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.rename_device(string_param(params, "mac"), string_param(params, "name")),
+          status::BadRequest);
+    let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
+    Ok(Response::with((status::Ok, serialized)))
+}
+
+define_handler!(ChangeDeviceMacHandler, change_device_mac);
+fn change_device_mac(
+        scheduler: AppServerSchedulerWrapped, app_server: &mut AppServer,
+        req: &mut Request) -> IronResult<Response> {
+    scheduler.kick_scheduler();
+    let params = itry!(req.get_ref::<Params>());
+    itry!(app_server.change_device_mac(string_param(params, "mac"),
+                                       string_param(params, "new_mac")),
+          status::BadRequest);
     let serialized = itry!(serde_json::to_string_pretty(&app_server.world));
     Ok(Response::with((status::Ok, serialized)))
 }
@@ -161,6 +446,39 @@ fn refresh_devices(
     Ok(Response::with((status::Ok, serialized)))
 }
 
+#[derive(Serialize)]
+struct LoginResponse {
+    token: String,
+}
+
+struct LoginHandler {
+    auth: AuthWrapped,
+}
+
+impl Handler for LoginHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let params = itry!(req.get_ref::<Params>());
+        let password = string_param(params, "password").unwrap_or("");
+        let token = itry!(self.auth.login(password, Utc::now()), status::Unauthorized);
+        let cookie = session_cookie(&token, Duration::days(SESSION_DAYS).num_seconds());
+        let serialized = itry!(serde_json::to_string_pretty(&LoginResponse { token }));
+        Ok(Response::with((status::Ok, Header(SetCookie(vec![cookie])), serialized)))
+    }
+}
+
+struct LogoutHandler {
+    auth: AuthWrapped,
+}
+
+impl Handler for LogoutHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        if let Some(token) = request_token(req) {
+            self.auth.logout(&token);
+        }
+        let cookie = session_cookie("", 0);
+        Ok(Response::with((status::Ok, Header(SetCookie(vec![cookie])))))
+    }
+}
 
 struct StaticHandler {
     buf: &'static [u8],
@@ -187,13 +505,28 @@ impl Handler for StaticHandler {
     }
 }
 
-pub fn run_server(app_server_wrapped: &AppServerSchedulerWrapped) {
+pub fn run_server(app_server_wrapped: &AppServerSchedulerWrapped, auth: AuthWrapped) {
     let mut router = Router::new();
 
+    router.post("/api/login", LoginHandler { auth: auth.clone() }, "login");
+    router.post("/api/logout", LogoutHandler { auth: auth.clone() }, "logout");
+
     router.get(
         "/api",
         GetWorldHandler::new(app_server_wrapped.clone()),
         "get_world");
+    router.get(
+        "/api/history",
+        GetHistoryHandler::new(app_server_wrapped.clone()),
+        "get_history");
+    router.get(
+        "/api/firewall",
+        GetFirewallStatusHandler::new(app_server_wrapped.clone()),
+        "get_firewall_status");
+    router.post(
+        "/api/preview",
+        PreviewChangeHandler::new(app_server_wrapped.clone()),
+        "preview_change");
     router.post(
         "/api/device/open",
         OpenDeviceHandler::new(app_server_wrapped.clone()),
@@ -202,15 +535,59 @@ pub fn run_server(app_server_wrapped: &AppServerSchedulerWrapped) {
         "/api/device/close",
         CloseDeviceHandler::new(app_server_wrapped.clone()),
         "close_device");
+    router.post(
+        "/api/device/extend",
+        ExtendDeviceHandler::new(app_server_wrapped.clone()),
+        "extend_device");
+    router.post(
+        "/api/device/schedule",
+        ScheduleDeviceHandler::new(app_server_wrapped.clone()),
+        "schedule_device");
+    router.post(
+        "/api/device/cancel_pending",
+        CancelPendingHandler::new(app_server_wrapped.clone()),
+        "cancel_pending");
+    router.post(
+        "/api/group/open",
+        OpenGroupHandler::new(app_server_wrapped.clone()),
+        "open_group");
+    router.post(
+        "/api/group/close",
+        CloseGroupHandler::new(app_server_wrapped.clone()),
+        "close_group");
     router.post("/api/guest",
         SetGuestHandler::new(app_server_wrapped.clone()),
         "set_guest");
     router.post("/api/override_all",
         SetOverrideAllHandler::new(app_server_wrapped.clone()),
         "set_override_all");
+    router.post("/api/rule/add",
+        AddWeeklyRuleHandler::new(app_server_wrapped.clone()),
+        "add_weekly_rule");
+    router.post("/api/rule/remove",
+        RemoveWeeklyRuleHandler::new(app_server_wrapped.clone()),
+        "remove_weekly_rule");
     router.post("/api/add_device",
         AddDeviceHandler::new(app_server_wrapped.clone()),
         "add_device");
+    router.post("/api/device/adopt",
+        AdoptDeviceHandler::new(app_server_wrapped.clone()),
+        "adopt_device");
+    router.post("/api/device/ignore",
+        IgnoreDeviceHandler::new(app_server_wrapped.clone()),
+        "ignore_device");
+    router.post("/api/device/unignore",
+        UnignoreDeviceHandler::new(app_server_wrapped.clone()),
+        "unignore_device");
+    router.post("/api/device/remove",
+        RemoveDeviceHandler::new(app_server_wrapped.clone()),
+        "remove_device");
+    router.post("/api/device/rename",
+        RenameDeviceHandler::new(app_server_wrapped.clone()),
+        "rename_device");
+    router.post("/api/device/mac",
+        ChangeDeviceMacHandler::new(app_server_wrapped.clone()),
+        "change_device_mac");
     router.post("/api/refresh_devices",
         RefreshDevicesHandler::new(app_server_wrapped.clone()),
         "refresh_devices");
@@ -237,6 +614,60 @@ pub fn run_server(app_server_wrapped: &AppServerSchedulerWrapped) {
     let graphiql_endpoint = GraphiQLHandler::new("/graphql");
     router.get("/graphiql", graphiql_endpoint, "graphiql");
 
+    let mut chain = Chain::new(router);
+    chain.link_before(AuthMiddleware::new(auth));
+
     let bind = "0.0.0.0:8000";
-    Iron::new(router).http(bind).unwrap();
+    Iron::new(chain).http(bind).unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use params::{Map, Value};
+    use chrono::{Utc, TimeZone, Duration, MAX_DATE};
+    use server::time_bound_param;
+
+    fn params(pairs: &[(&str, &str)]) -> Map {
+        let mut map = Map::new();
+        for &(name, value) in pairs {
+            map.assign(name, Value::String(value.to_owned())).unwrap();
+        }
+        map
+    }
+
+    #[test]
+    fn time_bound() {
+        let now = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        assert_eq!(None, time_bound_param(&params(&[]), now).unwrap());
+        assert_eq!(Some(now + Duration::minutes(30)),
+                   time_bound_param(&params(&[("time_secs", "1800")]), now).unwrap());
+        assert_eq!(Some(now + Duration::hours(1)),
+                   time_bound_param(&params(&[("until", "2018-03-01T11:00:00Z")]), now)
+                       .unwrap());
+        for bad in &[vec![("time_secs", "9223372036854775807")],
+                     vec![("time_secs", "-9223372036854775808")],
+                     vec![("time_secs", "99999999999999999999")],
+                     vec![("time_secs", "0")],
+                     vec![("time_secs", "-60")],
+                     vec![("time_secs", "ten")],
+                     vec![("until", "2018-03-01T09:00:00Z")],
+                     vec![("until", "tomorrow")],
+                     vec![("until", "2019-03-02T10:00:01Z")],
+                     vec![("until", "+262143-12-31T00:00:00Z")],
+                     vec![("time_secs", "60"), ("until", "2018-03-01T11:00:00Z")]] {
+            assert!(time_bound_param(&params(bad), now).is_err(), "{:?} accepted", bad);
+        }
+    }
+
+    #[test]
+    fn scheduled_time_bound() {
+        // Scheduled openings count time_secs from a start the caller picks.
+        let start = Utc.ymd(2018, 3, 1).and_hms(10, 0, 0);
+        assert_eq!(Some(start + Duration::hours(1)),
+                   time_bound_param(&params(&[("time_secs", "3600")]), start).unwrap());
+        let huge = params(&[("time_secs", "9223372036854775807")]);
+        assert!(time_bound_param(&huge, start).is_err());
+        let year = params(&[("time_secs", "31536000")]);
+        assert!(time_bound_param(&year, MAX_DATE.and_hms(0, 0, 0)).is_err());
+    }
 }
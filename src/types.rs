@@ -1,11 +1,13 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, BTreeMap};
 use std::iter::FromIterator;
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Utc, NaiveTime};
 use juniper::{GraphQLType};
+pub use mac::MacAddress;
+use leases::Lease;
 
 #[derive(Serialize, Deserialize, Debug, GraphQLObject)]
 pub struct Entry {
-    pub mac: String,
+    pub mac: MacAddress,
 }
 
 #[derive(Serialize, Deserialize, Debug, GraphQLObject)]
@@ -27,7 +29,9 @@ pub struct Table {
 )]
 pub struct Device {
     pub name: String,
-    pub mac: String,
+    pub mac: MacAddress,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub daily_quota_mins: Option<i32>,
 }
 
 #[derive(
@@ -61,6 +65,30 @@ graphql_object!(ScheduleEntry<Device>: () as "ScheduleEntryDevice" |&self| {
     field time_bound() -> Option<DateTime<Utc>> {self.time_bound},
 });
 
+/// A device opening queued to take effect at `start`.
+#[derive(
+    Debug,
+    Clone,
+    Eq,
+    Ord,
+    PartialOrd,
+    PartialEq,
+    Hash,
+    Serialize,
+    Deserialize,
+)]
+pub struct PendingEntry {
+    pub item: Device,
+    pub time_bound: Option<DateTime<Utc>>,
+    pub start: DateTime<Utc>,
+}
+
+graphql_object!(PendingEntry: () |&self| {
+    field item() -> &Device {&self.item},
+    field time_bound() -> Option<DateTime<Utc>> {self.time_bound},
+    field start() -> DateTime<Utc> {self.start},
+});
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, GraphQLEnum)]
 pub enum DeviceOverride {
     Open,
@@ -78,6 +106,9 @@ pub struct Schedule {
     pub guest_entry: ScheduleEntry<GuestPath>,
     pub override_entry: Option<ScheduleEntry<DeviceOverride>>,
     pub open_device_entries: BTreeSet<ScheduleEntry<Device>>,
+    /// Openings queued for later.
+    #[serde(default)]
+    pub pending_device_entries: BTreeSet<PendingEntry>,
 }
 
 fn set_to_vec<T: ::std::clone::Clone>(input: &BTreeSet<T>) -> Vec<T> {
@@ -88,26 +119,254 @@ graphql_object!(Schedule: () |&self| {
     field guest_entry() -> &ScheduleEntry<GuestPath> {&self.guest_entry},
     field override_entry() -> &Option<ScheduleEntry<DeviceOverride>> {&self.override_entry},
     field open_device_entries() -> Vec<ScheduleEntry<Device>> {set_to_vec(&self.open_device_entries)},
+    field pending_device_entries() -> Vec<PendingEntry> {
+        set_to_vec(&self.pending_device_entries)
+    },
+});
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Eq,
+    Ord,
+    PartialOrd,
+    PartialEq,
+    Hash,
+    Serialize,
+    Deserialize,
+    GraphQLEnum,
+)]
+pub enum Day {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+/// Opens a device every week on the given days between `start` and `end`
+/// local time. An `end` at or before `start` means the window runs past
+/// midnight into the next day.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct WeeklyRule {
+    pub id: u32,
+    pub mac: MacAddress,
+    pub days: BTreeSet<Day>,
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+    /// Start of the most recent window that opened the device, so a device
+    /// closed by hand during a window is not reopened until the next one.
+    pub last_applied: Option<DateTime<Utc>>,
+}
+
+graphql_object!(WeeklyRule: () |&self| {
+    field id() -> i32 {self.id as i32},
+    field mac() -> &MacAddress {&self.mac},
+    field days() -> Vec<Day> {set_to_vec(&self.days)},
+    field start() -> String {self.start.format("%H:%M").to_string()},
+    field end() -> String {self.end.format("%H:%M").to_string()},
+    field last_applied() -> Option<DateTime<Utc>> {self.last_applied},
 });
 
+/// Screen time used by a device with a daily quota during the current period.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct QuotaUsage {
+    pub mac: MacAddress,
+    pub period_start: DateTime<Utc>,
+    pub used_secs: i64,
+    pub remaining_mins: i64,
+}
+
+graphql_object!(QuotaUsage: () |&self| {
+    field mac() -> &MacAddress {&self.mac},
+    field period_start() -> DateTime<Utc> {self.period_start},
+    field used_mins() -> i32 {(self.used_secs / 60) as i32},
+    field remaining_mins() -> i32 {self.remaining_mins as i32},
+});
+
+#[derive(Debug, Clone, Eq, PartialEq, GraphQLObject)]
+pub struct Group {
+    pub name: String,
+    pub macs: Vec<MacAddress>,
+}
+
+/// A MAC which has shown up on the network without being a known device.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, GraphQLObject)]
+pub struct SeenDevice {
+    pub mac: MacAddress,
+    pub name: String,
+    pub first_seen: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct World {
     pub schedule: Schedule,
     pub closed_devices: BTreeSet<Device>,
     pub unknown_devices: BTreeSet<Device>,
+    #[serde(default)]
+    pub weekly_rules: Vec<WeeklyRule>,
+    #[serde(default)]
+    pub quota_usage: Vec<QuotaUsage>,
+    /// When screen time was last added to `quota_usage`.
+    #[serde(default)]
+    pub quota_checked: Option<DateTime<Utc>>,
+    /// Copy of `Config::groups`, kept up to date by `reconcile_config`.
+    #[serde(default)]
+    pub groups: BTreeMap<String, BTreeSet<MacAddress>>,
+    /// Copy of `Config::ignored_devices`, kept up to date by `reconcile_config`.
+    #[serde(default)]
+    pub ignored_devices: BTreeSet<Device>,
+    /// Every unknown device ever seen. Entries are kept after the device
+    /// leaves the network so it is not reported as new when it returns.
+    #[serde(default)]
+    pub seen_devices: Vec<SeenDevice>,
+    /// The DHCP leases as last read, giving the IP and lease expiry of the
+    /// devices above.
+    #[serde(default)]
+    pub leases: Vec<Lease>,
 }
 
 graphql_object!(World: () |&self| {
     field schedule() -> &Schedule {&self.schedule},
     field closed_devices() -> Vec<Device> {set_to_vec(&self.closed_devices)},
     field unknown_devices() -> Vec<Device> {set_to_vec(&self.unknown_devices)},
+    field weekly_rules() -> &Vec<WeeklyRule> {&self.weekly_rules},
+    field quota_usage() -> &Vec<QuotaUsage> {&self.quota_usage},
+    field seen_devices() -> &Vec<SeenDevice> {&self.seen_devices},
+    field leases() -> &Vec<Lease> {&self.leases},
+    field ignored_devices() -> Vec<Device> {set_to_vec(&self.ignored_devices)},
+    field groups() -> Vec<Group> {
+        self.groups
+            .iter()
+            .map(|(name, macs)| Group {name: name.clone(), macs: set_to_vec(macs)})
+            .collect()
+    },
 });
 
 
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firewall {
+    Iptables,
+    Nftables,
+    Noop,
+}
+
+impl Default for Firewall {
+    fn default() -> Firewall {
+        Firewall::Iptables
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LeaseFormat {
+    Dnsmasq,
+    Odhcpd,
+    Isc,
+}
+
+impl Default for LeaseFormat {
+    fn default() -> LeaseFormat {
+        LeaseFormat::Dnsmasq
+    }
+}
+
+/// What happens to devices in `World::unknown_devices`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPolicy {
+    /// No rules of their own, so they follow the guest path.
+    Guest,
+    Allow,
+    Block,
+    /// Allowed for this many minutes after being first seen, then blocked.
+    Quarantine(u32),
+}
+
+impl Default for UnknownPolicy {
+    fn default() -> UnknownPolicy {
+        UnknownPolicy::Guest
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub exit_interfaces: BTreeSet<String>,
     pub state_file: String,
     pub dhcp_lease_file: String,
+    #[serde(default)]
+    pub lease_format: LeaseFormat,
+    /// IPv4 neighbor table in `/proc/net/arp` format.
+    #[serde(default = "default_arp_file")]
+    pub arp_file: Option<String>,
+    /// Command printing the IPv6 neighbor table in `ip neigh` format.
+    #[serde(default = "default_ip6_neighbor_command")]
+    pub ip6_neighbor_command: Option<Vec<String>>,
     pub known_devices: BTreeSet<Device>,
+    /// Named sets of device MACs which can be opened and closed together.
+    #[serde(default)]
+    pub groups: BTreeMap<String, BTreeSet<MacAddress>>,
+    /// Infrastructure such as printers and access points. They are always let
+    /// through and never listed as unknown.
+    #[serde(default)]
+    pub ignored_devices: BTreeSet<Device>,
+    /// Local time of day when daily quotas start over.
+    #[serde(default = "midnight")]
+    pub quota_reset_time: NaiveTime,
+    #[serde(default)]
+    pub firewall: Firewall,
+    #[serde(default)]
+    pub unknown_device_policy: UnknownPolicy,
+    /// Hash printed by `--hash-password`. The API needs no login when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub admin_password_hash: Option<String>,
+    /// JSON lines log of access changes. No history is kept when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_file: Option<String>,
+    /// The history moves aside to `<history_file>.1` past this size. Every
+    /// change appends to it, so on flash storage put `history_file` in RAM
+    /// (e.g. `/tmp`) at the cost of losing it on reboot.
+    #[serde(default = "default_history_max_bytes")]
+    pub history_max_bytes: u64,
+    /// How often the scheduler looks for new devices. Unset turns it off.
+    #[serde(default = "default_discovery_interval_secs")]
+    pub discovery_interval_secs: Option<u32>,
+    /// How often the live firewall is compared with the expected rules.
+    #[serde(default = "default_drift_check_secs")]
+    pub drift_check_secs: Option<u32>,
+    /// URL which gets a JSON `SeenDevice` POSTed when a new device shows up.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_device_webhook: Option<String>,
+    /// Command run when a new device shows up, with `DEVICE_MAC`,
+    /// `DEVICE_NAME` and `DEVICE_FIRST_SEEN` in its environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_device_command: Option<Vec<String>>,
+}
+
+fn default_arp_file() -> Option<String> {
+    Some("/proc/net/arp".to_owned())
+}
+
+fn default_ip6_neighbor_command() -> Option<Vec<String>> {
+    Some(vec!["ip".to_owned(), "-6".to_owned(), "neigh".to_owned()])
+}
+
+fn default_discovery_interval_secs() -> Option<u32> {
+    Some(300)
+}
+
+fn default_drift_check_secs() -> Option<u32> {
+    Some(60)
+}
+
+fn default_history_max_bytes() -> u64 {
+    256 * 1024
+}
+
+fn midnight() -> NaiveTime {
+    NaiveTime::from_hms(0, 0, 0)
 }